@@ -0,0 +1,106 @@
+use std::io;
+
+/// Number of bits needed to store any value in `0..count`.
+pub fn bits_for(count: usize) -> u8 {
+    (usize::BITS - count.saturating_sub(1).leading_zeros()) as u8
+}
+
+/// Accumulates values of arbitrary bit width MSB-first into a byte vector.
+#[derive(Debug, Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    acc_bits: u8,
+}
+
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter::default()
+    }
+
+    pub fn write(&mut self, value: u32, bits: u8) {
+        assert!(bits <= 32, "can't write more than 32 bits at once");
+        assert!(bits == 32 || value >> bits == 0, "{} does not fit into {} bits", value, bits);
+        self.acc = (self.acc << bits) | value as u64;
+        self.acc_bits += bits;
+        while self.acc_bits >= 8 {
+            self.acc_bits -= 8;
+            self.bytes.push((self.acc >> self.acc_bits) as u8);
+        }
+        self.acc &= (1 << self.acc_bits) - 1;
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.acc_bits > 0 {
+            self.bytes.push((self.acc << (8 - self.acc_bits)) as u8);
+        }
+        self.bytes
+    }
+}
+
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    acc: u64,
+    acc_bits: u8,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader { bytes, pos: 0, acc: 0, acc_bits: 0 }
+    }
+
+    pub fn read(&mut self, bits: u8) -> io::Result<u32> {
+        assert!(bits <= 32, "can't read more than 32 bits at once");
+        while self.acc_bits < bits {
+            let byte = *self.bytes.get(self.pos)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "bit stream is too short"))?;
+            self.pos += 1;
+            self.acc = (self.acc << 8) | byte as u64;
+            self.acc_bits += 8;
+        }
+        self.acc_bits -= bits;
+        let value = (self.acc >> self.acc_bits) as u32 & ((1u64 << bits) - 1) as u32;
+        self.acc &= (1 << self.acc_bits) - 1;
+        Ok(value)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bits::*;
+
+    #[test]
+    fn bits_for_counts_index_width() {
+        assert_eq!(bits_for(0), 0);
+        assert_eq!(bits_for(1), 0);
+        assert_eq!(bits_for(2), 1);
+        assert_eq!(bits_for(5), 3);
+        assert_eq!(bits_for(256), 8);
+        assert_eq!(bits_for(257), 9);
+        assert_eq!(bits_for(usize::MAX), 64);
+    }
+
+    #[test]
+    fn reads_back_values_of_mixed_width() {
+        let values = [(5, 3), (0, 0), (1, 1), (300, 9), (0xdead_beef, 32), (7, 3)];
+        let mut writer = BitWriter::new();
+        for &(v, bits) in values.iter() {
+            writer.write(v, bits);
+        }
+        let bytes = writer.finish();
+        assert_eq!(bytes.len(), (3 + 1 + 9 + 32 + 3usize).div_ceil(8));
+        let mut reader = BitReader::new(&bytes);
+        for &(v, bits) in values.iter() {
+            assert_eq!(reader.read(bits).unwrap(), v);
+        }
+    }
+
+    #[test]
+    fn reading_past_the_end_fails() {
+        let mut reader = BitReader::new(&[0xff]);
+        assert_eq!(reader.read(6).unwrap(), 0x3f);
+        assert!(reader.read(3).is_err());
+    }
+}
@@ -1,21 +1,117 @@
 use std::cmp;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, PartialEq, Clone, Copy, Eq)]
 pub enum Transform {
     HeadToTop, HeadToRight, HeadToBottom, HeadToLeft,
     HeadToTopInv, HeadToRightInv, HeadToBottomInv, HeadToLeftInv,
 }
 
+impl Transform {
+    /// Every transform, in the order the container numbers them.
+    pub const ALL: [Transform; 8] = [
+        Transform::HeadToTop, Transform::HeadToRight, Transform::HeadToBottom, Transform::HeadToLeft,
+        Transform::HeadToTopInv, Transform::HeadToRightInv, Transform::HeadToBottomInv, Transform::HeadToLeftInv,
+    ];
+
+    /// Where the pixel at `(x, y)` of the transformed rect comes from in the
+    /// original `width x height` one.
+    pub fn source_of(self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        let (x_rev, y_rev, ord_rev) = match self {
+            Transform::HeadToTop => (false, false, false),
+            Transform::HeadToRight => (true, false, true),
+            Transform::HeadToBottom => (true, true, false),
+            Transform::HeadToLeft => (false, true, true),
+            Transform::HeadToTopInv => (true, false, false),
+            Transform::HeadToRightInv => (false, false, true),
+            Transform::HeadToBottomInv => (false, true, false),
+            Transform::HeadToLeftInv => (true, true, true),
+        };
+        let (x, y) = if ord_rev { (y, x) } else { (x, y) };
+        (if x_rev { width - x - 1 } else { x }, if y_rev { height - y - 1 } else { y })
+    }
+
+    /// The single transform that does the same as applying `self` and then `next`.
+    pub fn then(self, next: Transform) -> Transform {
+        // an isometry of a square is fully determined by where the corners go
+        let corners = [(0, 0), (1, 0), (0, 1), (1, 1)];
+        let composed = |x, y| {
+            let (x, y) = next.source_of(x, y, 2, 2);
+            self.source_of(x, y, 2, 2)
+        };
+        *Transform::ALL.iter()
+            .find(|t| corners.iter().all(|&(x, y)| t.source_of(x, y, 2, 2) == composed(x, y)))
+            .unwrap()
+    }
+
+    pub fn inverse(self) -> Transform {
+        *Transform::ALL.iter().find(|&&t| self.then(t) == Transform::HeadToTop).unwrap()
+    }
+
+    /// Whether the transform swaps width and height.
+    pub fn transposes(self) -> bool {
+        matches!(self, Transform::HeadToRight | Transform::HeadToLeft |
+            Transform::HeadToRightInv | Transform::HeadToLeftInv)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct LinearCoeffs {
     pub shift: i16,
     pub factor: f32
 }
 
-fn rect_avg(vec: &Vec<Vec<u8>>, x: usize, y: usize, side: usize) -> u8 {
+impl LinearCoeffs {
+    pub fn apply(self, x: u8) -> u8 {
+        (x as f32).mul_add(self.factor, self.shift as f32).clamp(0.0, 255.0) as u8
+    }
+}
+
+/// The sums a least squares fit of `self` onto `other` is made of.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct MatchSums {
+    pub count: i64,
+    pub self_sum: i64,
+    pub self_sqr_sum: i64,
+    pub other_sum: i64,
+    pub prod_sum: i64,
+}
+
+impl MatchSums {
+    /// Least squares fit with `|factor| <= max_factor`; when the factor has to
+    /// be clamped, the shift is fitted again for the clamped factor.
+    pub fn coeffs(&self, max_factor: f32) -> LinearCoeffs {
+        let MatchSums { count, self_sum, self_sqr_sum, other_sum, prod_sum } = *self;
+        let det = self_sqr_sum * count - self_sum * self_sum;
+        let shift_for = |factor: f32| ((other_sum as f32 - factor * self_sum as f32) / count as f32).round() as i16;
+        if det == 0 {
+            return LinearCoeffs { shift: shift_for(0.0), factor: 0.0 };
+        }
+        let factor = ((prod_sum * count) as f32 - (self_sum * other_sum) as f32) / det as f32;
+        if factor.abs() > max_factor {
+            let clamped = max_factor.copysign(factor);
+            return LinearCoeffs { shift: shift_for(clamped), factor: clamped };
+        }
+        LinearCoeffs {
+            shift: ((self_sqr_sum * other_sum - prod_sum * self_sum) as f32 / det as f32).round() as i16,
+            factor
+        }
+    }
+
+    /// Squared error of `self.linear(c)` against `other`, given the sum of
+    /// squares of `other`. Worked out from the sums alone, so it ignores the
+    /// clamping and truncation `linear` does to the pixels.
+    pub fn sqr_error(&self, c: LinearCoeffs, other_sqr_sum: i64) -> f64 {
+        let (f, s) = (c.factor as f64, c.shift as f64);
+        f * f * self.self_sqr_sum as f64 + 2.0 * f * s * self.self_sum as f64 - 2.0 * f * self.prod_sum as f64
+            + self.count as f64 * s * s - 2.0 * s * self.other_sum as f64 + other_sqr_sum as f64
+    }
+}
+
+fn rect_avg(vec: &[Vec<u8>], x: usize, y: usize, side: usize) -> u8 {
     (vec[y..y+side].iter()
         .map(|ln| ln[x..x + side].iter().fold(0, |a, &el| a + el as usize))
-        .fold(0, |x, y| x + y as usize) / (side * side)) as u8
+        .sum::<usize>() / (side * side)) as u8
 }
 
 pub trait ByteRect where Self: Sized {
@@ -23,10 +119,19 @@ pub trait ByteRect where Self: Sized {
     fn get_square(&self, sq: SquareCoords) -> Self {
         self.get_rect(sq.x, sq.y, sq.side, sq.side)
     }
-    fn transform(&self, Transform) -> Self;
+    fn get_rect_at(&self, r: RectCoords) -> Self {
+        self.get_rect(r.x, r.y, r.width, r.height)
+    }
+    fn transform(&self, _: Transform) -> Self;
     fn scale_down(&self, times: usize) -> Self;
-    fn linear(&self, LinearCoeffs) -> Self;
-    fn best_coeffs_to_match(&self, other: &Self) -> LinearCoeffs;
+    fn linear(&self, _: LinearCoeffs) -> Self;
+    #[allow(dead_code)]
+    fn best_coeffs_to_match(&self, other: &Self) -> LinearCoeffs {
+        self.best_coeffs_to_match_within(other, f32::INFINITY)
+    }
+    /// Least squares fit with `|factor| <= max_factor`, see `MatchSums::coeffs`.
+    #[allow(dead_code)]
+    fn best_coeffs_to_match_within(&self, other: &Self, max_factor: f32) -> LinearCoeffs;
     fn dist(&self, other: &Self) -> u64;
     fn roughness(&self) -> u64 {
         let w = self.width();
@@ -40,6 +145,7 @@ pub trait ByteRect where Self: Sized {
         }
     }
     fn pad_to_divisible_by(&self, divisor: usize) -> Self;
+    #[allow(dead_code)]
     fn to_square_chunks(&self, size: usize) -> Vec<(SquareCoords, Self)>;
     fn width(&self) -> usize;
     fn height(&self) -> usize;
@@ -52,6 +158,56 @@ pub struct SquareCoords {
     pub side: usize,
 }
 
+#[derive(Debug, PartialEq, Clone, Copy, Eq)]
+pub struct RectCoords {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl From<SquareCoords> for RectCoords {
+    fn from(sq: SquareCoords) -> RectCoords {
+        RectCoords { x: sq.x, y: sq.y, width: sq.side, height: sq.side }
+    }
+}
+
+impl RectCoords {
+    pub fn is_square(&self) -> bool {
+        self.width == self.height
+    }
+}
+
+/// Row-major tiling of a `width x height` area with squares of the given side.
+pub fn square_grid(width: usize, height: usize, side: usize) -> Vec<SquareCoords> {
+    (0..height / side)
+        .flat_map(|chunk_y| (0..width / side)
+            .map(move |chunk_x| SquareCoords {
+                x: chunk_x * side,
+                y: chunk_y * side,
+                side,
+            }))
+        .collect()
+}
+
+/// Distance between neighbouring squares of the given side on a lattice of
+/// the given step: 0, like any step past the side, tiles the area.
+pub fn lattice_step(step: usize, side: usize) -> usize {
+    if step == 0 { side } else { step.min(side) }
+}
+
+/// Row-major squares of the given side within a `width x height` area whose
+/// corners lie `lattice_step(step, side)` apart on either axis.
+pub fn square_lattice(width: usize, height: usize, side: usize, step: usize) -> Vec<SquareCoords> {
+    if side > width || side > height {
+        return Vec::new();
+    }
+    let step = lattice_step(step, side);
+    (0..=height - side).step_by(step)
+        .flat_map(|y| (0..=width - side).step_by(step).map(move |x| SquareCoords { x, y, side }))
+        .collect()
+}
+
 impl ByteRect for Vec<Vec<u8>> {
     fn width(&self) -> usize {
         self[0].len()
@@ -64,33 +220,22 @@ impl ByteRect for Vec<Vec<u8>> {
     fn transform(&self, t: Transform) -> Self {
         let width = self[0].len();
         let height = self.len();
-        let (x_rev, y_rev, ord_rev) = match t {
-            Transform::HeadToTop => (false, false, false),
-            Transform::HeadToRight => (true, false, true),
-            Transform::HeadToBottom => (true, true, false),
-            Transform::HeadToLeft => (false, true, true),
-            Transform::HeadToTopInv => (true, false, false),
-            Transform::HeadToRightInv => (false, false, true),
-            Transform::HeadToBottomInv => (false, true, false),
-            Transform::HeadToLeftInv => (true, true, true),
-        };
-        let translate = |x: usize, y: usize| {
-            let x_trans = if x_rev {width - x - 1} else {x};
-            let y_trans = if y_rev {height - y - 1} else {y};
-            self[y_trans][x_trans]
-        };
-        if ord_rev {
-            (0..width).map(|x| (0..height).map(|y| translate(x, y)).collect()).collect()
-        } else {
-            (0..height).map(|y| (0..width).map(|x| translate(x, y)).collect()).collect()
-        }
+        let (new_width, new_height) = if t.transposes() { (height, width) } else { (width, height) };
+        (0..new_height)
+            .map(|y| (0..new_width)
+                .map(|x| {
+                    let (src_x, src_y) = t.source_of(x, y, width, height);
+                    self[src_y][src_x]
+                })
+                .collect())
+            .collect()
     }
 
     fn scale_down(&self, times: usize) -> Self {
         let width = self[0].len();
         let height = self.len();
-        if height % times != 0 || width % times != 0 {
-            panic!(format!("Can only scale the multiples of {}", times))
+        if !height.is_multiple_of(times) || !width.is_multiple_of(times) {
+            panic!("Can only scale the multiples of {}", times)
         }
         (0..height/times).map(|y|
             (0..width/times).map(|x|
@@ -99,12 +244,10 @@ impl ByteRect for Vec<Vec<u8>> {
 
     fn linear(&self, c: LinearCoeffs) -> Self {
         self.iter().map(|ln|
-            ln.iter().map(|&x|
-                (x as f32).mul_add(c.factor, c.shift as f32).min(255.0).max(0.0) as u8)
-            .collect()).collect()
+            ln.iter().map(|&x| c.apply(x)).collect()).collect()
     }
 
-    fn best_coeffs_to_match(&self, other: &Self) -> LinearCoeffs {
+    fn best_coeffs_to_match_within(&self, other: &Self, max_factor: f32) -> LinearCoeffs {
         let width = self.width();
         let height = self.height();
         if other.height() != height || other.width() != width {
@@ -113,25 +256,21 @@ impl ByteRect for Vec<Vec<u8>> {
         }
         let self_sum = self.iter().map(|ln|
                 ln.iter().fold(0, |a, &el| a + el as i64))
-            .fold(0, |x, y| x + y);
+            .sum::<i64>();
         let other_sum = other.iter().map(|ln|
                     ln.iter().fold(0, |a, &el| a + el as i64))
-                .fold(0, |x, y| x + y);
+                .sum::<i64>();
         let self_sqr_sum = self.iter().map(|ln|
                 ln.iter().fold(0, |a, &el| a + el as i64 * el as i64))
-            .fold(0, |x, y| x + y);
+            .sum::<i64>();
         let count = (self.len() * self[0].len()) as i64;
         let prod_sum = (0..height)
             .map(|y|
                 (0..width)
                     .map(|x| self[y][x] as i64 * other[y][x] as i64)
-                    .fold(0, |a, el| a + el))
-            .fold(0, |a, el| a + el);
-        let det = self_sqr_sum * count - self_sum * self_sum;
-        LinearCoeffs {
-            shift: ((self_sqr_sum * other_sum - prod_sum * self_sum) as f32 / det as f32).round() as i16,
-            factor: ((prod_sum * count) as f32 - (self_sum * other_sum) as f32) / det as f32
-        }
+                    .sum::<i64>())
+            .sum::<i64>();
+        MatchSums { count, self_sum, self_sqr_sum, other_sum, prod_sum }.coeffs(max_factor)
     }
 
     fn get_rect(&self, x: usize, y: usize, width: usize, height: usize) -> Self {
@@ -153,12 +292,12 @@ impl ByteRect for Vec<Vec<u8>> {
                 .map(|x| self[y][x] as i32 - other[y][x] as i32)
                 .map(|diff| diff * diff)
                 .fold(0, |a, diff| a + diff as u64))
-            .fold(0, |a, el| a + el)
+            .sum()
     }
 
     fn pad_to_divisible_by(&self, divisor: usize) -> Self {
         let round = |x: usize| {
-            (x + divisor - 1) / divisor * divisor
+            x.div_ceil(divisor) * divisor
         };
         let orig_width = self[0].len();
         let orig_height = self.len();
@@ -174,18 +313,12 @@ impl ByteRect for Vec<Vec<u8>> {
     fn to_square_chunks(&self, size: usize) -> Vec<(SquareCoords, Self)> {
         let width = self[0].len();
         let height = self.len();
-        if height % size != 0 || width % size != 0 {
+        if !height.is_multiple_of(size) || !width.is_multiple_of(size) {
             panic!("can't chunk what is not divisible by chunk size");
         }
-        (0..height / size)
-            .flat_map(|chunk_y| (0..width / size)
-                .map(move |chunk_x| SquareCoords {
-                    x: chunk_x * size,
-                    y: chunk_y * size,
-                    side: size,
-                })
-                .map(|coords| (coords, self.get_square(coords)))
-            )
+        square_grid(width, height, size)
+            .into_iter()
+            .map(|coords| (coords, self.get_square(coords)))
             .collect()
     }
 }
@@ -195,6 +328,20 @@ impl ByteRect for Vec<Vec<u8>> {
 mod tests {
     use byte_rect::*;
 
+    #[test]
+    fn composed_transforms_match_applying_both() {
+        let rect = vec![
+            vec![1, 2, 3],
+            vec![4, 5, 6],
+        ];
+        for &a in Transform::ALL.iter() {
+            for &b in Transform::ALL.iter() {
+                assert_eq!(rect.transform(a).transform(b), rect.transform(a.then(b)), "{:?} then {:?}", a, b);
+            }
+            assert_eq!(rect.transform(a).transform(a.inverse()), rect);
+        }
+    }
+
     #[test]
     fn rotates_byte_rect() {
         let byte_rect = vec![
@@ -271,6 +418,38 @@ mod tests {
         assert_eq!(adjusted, desired);
     }
 
+    #[test]
+    fn least_squares_clamps_factor_and_refits_shift() {
+        let byte_rect = vec![
+            vec![0, 10],
+            vec![20, 30],
+        ];
+        let desired = vec![
+            vec![0, 20],
+            vec![40, 60],
+        ];
+        assert_eq!(byte_rect.best_coeffs_to_match(&desired), LinearCoeffs { shift: 0, factor: 2.0 });
+        assert_eq!(byte_rect.best_coeffs_to_match_within(&desired, 0.5), LinearCoeffs { shift: 23, factor: 0.5 });
+        let inverted = vec![
+            vec![60, 40],
+            vec![20, 0],
+        ];
+        assert_eq!(byte_rect.best_coeffs_to_match_within(&inverted, 0.5), LinearCoeffs { shift: 38, factor: -0.5 });
+    }
+
+    #[test]
+    fn least_squares_on_flat_rect_yields_the_mean() {
+        let byte_rect = vec![
+            vec![7, 7],
+            vec![7, 7],
+        ];
+        let desired = vec![
+            vec![10, 20],
+            vec![30, 41],
+        ];
+        assert_eq!(byte_rect.best_coeffs_to_match(&desired), LinearCoeffs { shift: 25, factor: 0.0 });
+    }
+
     #[test]
     fn euclid_dist_works_correctly() {
         let r1 = vec![
@@ -358,4 +537,15 @@ mod tests {
         ];
         assert_eq!(chunks, expected_chunks);
     }
+
+    #[test]
+    fn lattice_squares_overlap_by_the_step() {
+        let xs = |width, step| square_lattice(width, 8, 8, step).iter().map(|sq| sq.x).collect::<Vec<_>>();
+        assert_eq!(xs(20, 4), vec![0, 4, 8, 12]);
+        assert_eq!(xs(20, 0), vec![0, 8]);
+        assert_eq!(xs(20, 100), vec![0, 8]);
+        assert_eq!(square_lattice(16, 16, 8, 0), square_grid(16, 16, 8));
+        assert_eq!(square_lattice(16, 12, 8, 4).len(), 3 * 2);
+        assert!(square_lattice(4, 16, 8, 2).is_empty());
+    }
 }
@@ -21,7 +21,7 @@ fn rgb_to_ycrcb(y: u8, cr: u8, cb: u8) -> (u8, u8, u8) {
     (r as u8, g as u8, b as u8)
 }
 
-fn map_image<F, T>(image: &Vec<Vec<RgbPx>>, func: F) -> Vec<Vec<T>> where F: Fn(RgbPx) -> T {
+fn map_image<F, T>(image: &[Vec<RgbPx>], func: F) -> Vec<Vec<T>> where F: Fn(RgbPx) -> T {
     image
         .iter()
         .map(|ln| ln
@@ -31,7 +31,9 @@ fn map_image<F, T>(image: &Vec<Vec<RgbPx>>, func: F) -> Vec<Vec<T>> where F: Fn(
         .collect()
 }
 
-pub fn to_rgb_channels(image: &Vec<Vec<RgbPx>>) -> (Vec<Vec<u8>>, Vec<Vec<u8>>, Vec<Vec<u8>>) {
+pub type Channels = (Vec<Vec<u8>>, Vec<Vec<u8>>, Vec<Vec<u8>>);
+
+pub fn to_rgb_channels(image: &[Vec<RgbPx>]) -> Channels {
     (map_image(image, |px| px.r), map_image(image, |px| px.g), map_image(image, |px| px.b))
 }
 
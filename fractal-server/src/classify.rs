@@ -0,0 +1,147 @@
+// Fisher's classification of square blocks. The block is split into four
+// quadrants; one of the eight isometries brings their brightness into one of
+// three canonical orders, and the order of their variances in that
+// orientation picks one of 24 subclasses. Blocks that look alike end up in
+// the same class and, once both are in canonical orientation, line up
+// without trying the other seven transforms.
+
+use byte_rect::Transform;
+use view::PixelSource;
+
+pub const CLASS_COUNT: usize = 3 * 24;
+
+/// Quadrants in clockwise order from the top left, as `(x, y)` on a 2x2 grid.
+const CLOCKWISE: [(usize, usize); 4] = [(0, 0), (1, 0), (1, 1), (0, 1)];
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct BlockClass {
+    /// In `0..CLASS_COUNT`.
+    pub class: usize,
+    /// Brings the block into its canonical orientation.
+    pub orientation: Transform,
+}
+
+impl BlockClass {
+    /// The transform that turns a block of class `self` into one of class
+    /// `target`, when both are the same class.
+    pub fn transform_to(&self, target: &BlockClass) -> Transform {
+        self.orientation.then(target.orientation.inverse())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Quadrant {
+    sum: i64,
+    sqr_sum: i64,
+}
+
+/// Quadrants on a 2x2 grid; the middle row and column of odd sized blocks
+/// belong to none of them.
+fn quadrants<S: PixelSource>(block: &S) -> [[Quadrant; 2]; 2] {
+    let (half_w, half_h) = (block.width() / 2, block.height() / 2);
+    let (skip_w, skip_h) = (block.width() - half_w, block.height() - half_h);
+    let mut quads = [[Quadrant::default(); 2]; 2];
+    for (qy, row) in quads.iter_mut().enumerate() {
+        for (qx, quad) in row.iter_mut().enumerate() {
+            for y in qy * skip_h..qy * skip_h + half_h {
+                for x in qx * skip_w..qx * skip_w + half_w {
+                    let p = block.at(x, y) as i64;
+                    quad.sum += p;
+                    quad.sqr_sum += p * p;
+                }
+            }
+        }
+    }
+    quads
+}
+
+/// Which of the three canonical brightness orders `b` is in, if any.
+fn brightness_class(b: &[i64; 4]) -> Option<usize> {
+    if b[0] >= b[1] && b[1] >= b[2] && b[2] >= b[3] {
+        Some(0)
+    } else if b[0] >= b[1] && b[1] >= b[3] && b[3] >= b[2] {
+        Some(1)
+    } else if b[0] >= b[2] && b[2] >= b[1] && b[1] >= b[3] {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+/// Index in `0..24` of the order the quadrants sort into by falling variance.
+fn variance_class(quads: &[Quadrant; 4], count: i64) -> usize {
+    let mut order = [0, 1, 2, 3];
+    // count times the variance, which orders the same
+    order.sort_by_key(|&i| -(count * quads[i].sqr_sum - quads[i].sum * quads[i].sum));
+    let mut rank = 0;
+    for i in 0..4 {
+        let smaller_later = order[i + 1..].iter().filter(|&&o| o < order[i]).count();
+        rank = rank * (4 - i) + smaller_later;
+    }
+    rank
+}
+
+pub fn classify<S: PixelSource>(block: &S) -> BlockClass {
+    let quads = quadrants(block);
+    let count = ((block.width() / 2) * (block.height() / 2)) as i64;
+    Transform::ALL.iter()
+        .filter_map(|&t| {
+            let mut oriented = [Quadrant::default(); 4];
+            for (quad, &(x, y)) in oriented.iter_mut().zip(CLOCKWISE.iter()) {
+                let (src_x, src_y) = t.source_of(x, y, 2, 2);
+                *quad = quads[src_y][src_x];
+            }
+            let brightness = [oriented[0].sum, oriented[1].sum, oriented[2].sum, oriented[3].sum];
+            brightness_class(&brightness)
+                .map(|major| BlockClass { class: major * 24 + variance_class(&oriented, count), orientation: t })
+        })
+        .next()
+        .expect("some isometry always sorts the quadrants canonically")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use classify::*;
+    use byte_rect::ByteRect;
+    use gray_image::GrayImage;
+
+    fn block() -> GrayImage {
+        GrayImage::from_fn(4, 4, |x, y| ((x * 67 + y * 29 + x * x * y * 13) % 256) as u8)
+    }
+
+    #[test]
+    fn classes_cover_all_orders() {
+        let mut seen = [false; CLASS_COUNT];
+        let mut state = 1u32;
+        for _ in 0..20000 {
+            let img = GrayImage::from_fn(4, 4, |_, _| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 24) as u8
+            });
+            let class = classify(&img.as_view());
+            assert!(class.class < CLASS_COUNT);
+            seen[class.class] = true;
+        }
+        assert!(seen.iter().all(|&s| s), "unseen classes: {:?}",
+            (0..CLASS_COUNT).filter(|&c| !seen[c]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn isometries_share_the_class() {
+        let img = block();
+        let class = classify(&img.as_view());
+        for &t in Transform::ALL.iter() {
+            assert_eq!(classify(&img.transform(t).as_view()).class, class.class, "{:?}", t);
+        }
+    }
+
+    #[test]
+    fn canonical_orientations_line_up() {
+        let img = block();
+        let target = img.transform(Transform::HeadToLeftInv);
+        let from = classify(&img.as_view());
+        let to = classify(&target.as_view());
+        assert_eq!(img.transform(from.transform_to(&to)), target);
+    }
+}
@@ -0,0 +1,360 @@
+use std::str::FromStr;
+
+use fractal_server::container::Coding;
+use fractal_server::{CompSettings, DecompSettings, OutputSize, Partition, Search, Target, Update};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Command {
+    Encode { input: String, output: String, settings: CompSettings, coding: Coding },
+    Decode { input: String, output: String, settings: DecompSettings },
+    Roundtrip { input: String, output: Option<String>, comp: CompSettings, decomp: DecompSettings, coding: Coding },
+    Serve { addr: String },
+}
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:8080";
+
+pub const USAGE: &str = "\
+usage:
+    fractal-server encode <in.png> <out.fic> [compression flags]
+    fractal-server decode <in.fic> <out.png> [decompression flags]
+    fractal-server roundtrip <in.png> [<out.png>] [compression flags] [decompression flags]
+    fractal-server serve [<address>]    (default 127.0.0.1:8080)
+
+compression flags:
+    --big-square-size <n>      side of domain blocks (default 16)
+    --small-square-size <n>    side of range blocks (default 4)
+    --grouping-factor <n>      only search the smoothest 1/n of domains (default 20)
+    --factor-bits <n>          bit depth of the contrast factor (default 5)
+    --shift-bits <n>           bit depth of the brightness shift (default 7)
+    --max-factor <x>           upper bound of |factor| (default 1.0)
+    --search <strategy>        how domains are searched (default smoothest):
+                                 exhaustive   every domain
+                                 smoothest    the smoothest 1/grouping-factor of them
+                                 classified   those of the same Fisher class
+                                 nearest:<k>  the k nearest in normalized feature space
+                                 local:<r>    those at most r pixels (in whole domain steps) away
+                                 spiral:<r>   the same, nearest first
+    --threads <n>              search threads, 0 for one per core (default 0)
+    --accept-mse <x>           stop a block's search at this mean squared error (default 0)
+    --partition <quadtree|hv>  how range blocks are split (default quadtree); hv
+                               blocks search every domain twice their size
+    --split-depth <n>          how many times range blocks may be split (default 0)
+    --split-mse <x>            split range blocks matched worse than this (default 100)
+    --domain-step <n>          pixels between domains, overlapping below their side;
+                               0 tiles the image with them (default 0)
+    --target-bpp <x>           pick splits and precisions per block to fit this
+                               many bits per pixel, instead of --split-mse
+    --target-psnr <x>          the same, to reach this PSNR in dB
+    --precision-levels <n>     coarser coefficient precisions targets may pick,
+                               one bit less each (default 0)
+    --coding <packed|range>    mapping entropy coding (default range)
+
+decompression flags:
+    --iterations <n>           most decoding passes (default 20)
+    --converge-mse <x>         stop after a pass changing the image by at most
+                               this mean squared error (default 0)
+    --update <jacobi|gauss-seidel>
+                               paint each pass from the previous one, or in
+                               place, which takes fewer passes (default jacobi)
+    --pyramid-levels <n>       decode at a half, quarter... of the size first,
+                               for this many levels (default 0)
+    --refine-passes <n>        most passes on every bigger level (default 2)
+    --scale <x>                decode at this multiple of the original size (default 1)
+    --size <WxH>               decode at exactly this size";
+
+fn parse_value<T: FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", flag))?;
+    value.parse().map_err(|_| format!("invalid value '{}' for {}", value, flag))
+}
+
+fn parse_size(value: &str) -> Result<OutputSize, String> {
+    let mut sides = value.splitn(2, 'x').map(|side| side.parse::<usize>());
+    match (sides.next(), sides.next()) {
+        (Some(Ok(width)), Some(Ok(height))) => Ok(OutputSize::Exact { width, height }),
+        _ => Err(format!("invalid size '{}', expected WIDTHxHEIGHT", value)),
+    }
+}
+
+fn parse_update(value: &str) -> Result<Update, String> {
+    match value {
+        "jacobi" => Ok(Update::Jacobi),
+        "gauss-seidel" => Ok(Update::GaussSeidel),
+        _ => Err(format!("unknown update '{}'", value)),
+    }
+}
+
+fn parse_coding(value: &str) -> Result<Coding, String> {
+    match value {
+        "packed" => Ok(Coding::Packed),
+        "range" => Ok(Coding::Range),
+        _ => Err(format!("unknown coding '{}'", value)),
+    }
+}
+
+fn parse_partition(value: &str) -> Result<Partition, String> {
+    match value {
+        "quadtree" => Ok(Partition::Quadtree),
+        "hv" => Ok(Partition::Hv),
+        _ => Err(format!("unknown partition '{}'", value)),
+    }
+}
+
+fn parse_search(value: &str) -> Result<Search, String> {
+    let mut parts = value.splitn(2, ':');
+    let strategy = parts.next().unwrap();
+    let param = parts.next().map(|p| p.to_string());
+    match strategy {
+        "exhaustive" => Ok(Search::Exhaustive),
+        "smoothest" => Ok(Search::Smoothest),
+        "classified" => Ok(Search::Classified),
+        "nearest" => Ok(Search::Nearest { k: parse_value("nearest", param.as_ref())? }),
+        "local" => Ok(Search::Local { radius: parse_value("local", param.as_ref())?, spiral: false }),
+        "spiral" => Ok(Search::Local { radius: parse_value("spiral", param.as_ref())?, spiral: true }),
+        _ => Err(format!("unknown search '{}'", value)),
+    }
+}
+
+/// Settings shared by the command line and the HTTP query string, where the
+/// same names are used without the leading dashes.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Options {
+    pub comp: CompSettings,
+    pub decomp: DecompSettings,
+    pub coding: Coding,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OptionKind {
+    Compression,
+    Decompression,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options { comp: CompSettings::default(), decomp: DecompSettings::default(), coding: Coding::Range }
+    }
+}
+
+impl Options {
+    pub fn set(&mut self, name: &str, value: Option<&String>) -> Result<OptionKind, String> {
+        let comp = &mut self.comp;
+        match name {
+            "big-square-size" => comp.big_square_size = parse_value(name, value)?,
+            "small-square-size" => comp.small_square_size = parse_value(name, value)?,
+            "grouping-factor" => comp.grouping_factor = parse_value(name, value)?,
+            "factor-bits" => comp.factor_bits = parse_value(name, value)?,
+            "shift-bits" => comp.shift_bits = parse_value(name, value)?,
+            "max-factor" => comp.max_factor = parse_value(name, value)?,
+            "search" => comp.search = parse_search(&parse_value::<String>(name, value)?)?,
+            "threads" => comp.threads = parse_value(name, value)?,
+            "accept-mse" => comp.accept_mse = parse_value(name, value)?,
+            "partition" => comp.partition = parse_partition(&parse_value::<String>(name, value)?)?,
+            "split-depth" => comp.split_depth = parse_value(name, value)?,
+            "split-mse" => comp.split_mse = parse_value(name, value)?,
+            "domain-step" => comp.domain_step = parse_value(name, value)?,
+            "target-bpp" => comp.target = Some(Target::Bpp(parse_value(name, value)?)),
+            "target-psnr" => comp.target = Some(Target::Psnr(parse_value(name, value)?)),
+            "precision-levels" => comp.precision_levels = parse_value(name, value)?,
+            "coding" => self.coding = parse_coding(&parse_value::<String>(name, value)?)?,
+            "iterations" => {
+                self.decomp.iterations = parse_value(name, value)?;
+                return Ok(OptionKind::Decompression);
+            }
+            "converge-mse" => {
+                self.decomp.converge_mse = parse_value(name, value)?;
+                return Ok(OptionKind::Decompression);
+            }
+            "pyramid-levels" => {
+                self.decomp.pyramid_levels = parse_value(name, value)?;
+                return Ok(OptionKind::Decompression);
+            }
+            "refine-passes" => {
+                self.decomp.refine_passes = parse_value(name, value)?;
+                return Ok(OptionKind::Decompression);
+            }
+            "update" => {
+                self.decomp.update = parse_update(&parse_value::<String>(name, value)?)?;
+                return Ok(OptionKind::Decompression);
+            }
+            "scale" => {
+                self.decomp.size = OutputSize::Scaled(parse_value(name, value)?);
+                return Ok(OptionKind::Decompression);
+            }
+            "size" => {
+                self.decomp.size = parse_size(&parse_value::<String>(name, value)?)?;
+                return Ok(OptionKind::Decompression);
+            }
+            _ => return Err(format!("unknown option {}", name)),
+        }
+        Ok(OptionKind::Compression)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.comp.validate()?;
+        if self.decomp.iterations == 0 {
+            return Err("iterations must be positive".to_string());
+        }
+        if self.decomp.converge_mse.is_nan() || self.decomp.converge_mse < 0.0 {
+            return Err("convergence error must not be negative".to_string());
+        }
+        if self.decomp.pyramid_levels >= usize::BITS as usize {
+            return Err("too many pyramid levels".to_string());
+        }
+        if self.decomp.refine_passes == 0 {
+            return Err("refine passes must be positive".to_string());
+        }
+        match self.decomp.size {
+            OutputSize::Scaled(factor) if !(factor > 0.0 && factor.is_finite()) =>
+                return Err("scale must be positive".to_string()),
+            OutputSize::Exact { width, height } if width == 0 || height == 0 =>
+                return Err("size must not be empty".to_string()),
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+struct Parsed {
+    positional: Vec<String>,
+    options: Options,
+    comp_flags: bool,
+    decomp_flags: bool,
+}
+
+fn parse_flags(args: &[String]) -> Result<Parsed, String> {
+    let mut parsed = Parsed {
+        positional: Vec::new(),
+        options: Options::default(),
+        comp_flags: false,
+        decomp_flags: false,
+    };
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if !arg.starts_with("--") {
+            parsed.positional.push(arg.clone());
+            continue;
+        }
+        match parsed.options.set(&arg[2..], iter.next())? {
+            OptionKind::Compression => parsed.comp_flags = true,
+            OptionKind::Decompression => parsed.decomp_flags = true,
+        }
+    }
+    Ok(parsed)
+}
+
+/// Parses the arguments following the program name.
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let (subcommand, rest) = args.split_first().ok_or("missing subcommand")?;
+    let Parsed { positional, options, comp_flags, decomp_flags } = parse_flags(rest)?;
+    options.validate()?;
+    let Options { comp, decomp, coding } = options;
+    match (subcommand.as_str(), positional.len()) {
+        ("encode", 2) if !decomp_flags => Ok(Command::Encode {
+            input: positional[0].clone(), output: positional[1].clone(), settings: comp, coding,
+        }),
+        ("decode", 2) if !comp_flags => Ok(Command::Decode {
+            input: positional[0].clone(), output: positional[1].clone(), settings: decomp,
+        }),
+        ("roundtrip", 1) | ("roundtrip", 2) => Ok(Command::Roundtrip {
+            input: positional[0].clone(), output: positional.get(1).cloned(), comp, decomp, coding,
+        }),
+        ("serve", 0) | ("serve", 1) if !comp_flags && !decomp_flags => Ok(Command::Serve {
+            addr: positional.first().cloned().unwrap_or_else(|| DEFAULT_ADDR.to_string()),
+        }),
+        ("encode", _) | ("decode", _) | ("roundtrip", _) | ("serve", _) =>
+            Err(format!("wrong arguments for {}", subcommand)),
+        _ => Err(format!("unknown subcommand {}", subcommand)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use cli::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn encode_takes_compression_flags() {
+        let cmd = parse(&args("encode in.png out.fic --small-square-size 8 --max-factor 0.9 --coding packed --search local:16 --domain-step 3")).unwrap();
+        assert_eq!(cmd, Command::Encode {
+            input: "in.png".to_string(),
+            output: "out.fic".to_string(),
+            settings: CompSettings {
+                small_square_size: 8,
+                max_factor: 0.9,
+                search: Search::Local { radius: 16, spiral: false },
+                domain_step: 3,
+                ..CompSettings::default()
+            },
+            coding: Coding::Packed,
+        });
+    }
+
+    #[test]
+    fn decode_takes_an_output_size() {
+        let decode = |line| match parse(&args(line)).unwrap() {
+            Command::Decode { settings, .. } => settings.size,
+            cmd => panic!("{:?}", cmd),
+        };
+        assert_eq!(decode("decode in.fic out.png --scale 2.5"), OutputSize::Scaled(2.5));
+        assert_eq!(decode("decode in.fic out.png --size 640x480"), OutputSize::Exact { width: 640, height: 480 });
+    }
+
+    #[test]
+    fn roundtrip_output_is_optional() {
+        let cmd = parse(&args("roundtrip in.png --iterations 5 --converge-mse 0.5 --update gauss-seidel --pyramid-levels 2 --target-psnr 30 --precision-levels 2")).unwrap();
+        assert_eq!(cmd, Command::Roundtrip {
+            input: "in.png".to_string(),
+            output: None,
+            comp: CompSettings { target: Some(Target::Psnr(30.0)), precision_levels: 2, ..CompSettings::default() },
+            decomp: DecompSettings { iterations: 5, converge_mse: 0.5, update: Update::GaussSeidel, pyramid_levels: 2, ..DecompSettings::default() },
+            coding: Coding::Range,
+        });
+    }
+
+    #[test]
+    fn rejects_flags_that_do_not_apply() {
+        assert!(parse(&args("decode in.fic out.png --factor-bits 3")).is_err());
+        assert!(parse(&args("encode in.png out.fic --iterations 3")).is_err());
+        assert!(parse(&args("decode in.fic out.png --scale 0")).is_err());
+        assert!(parse(&args("decode in.fic out.png --converge-mse -1")).is_err());
+        assert!(parse(&args("decode in.fic out.png --update sor")).is_err());
+        assert!(parse(&args("decode in.fic out.png --refine-passes 0")).is_err());
+        assert!(parse(&args("decode in.fic out.png --size 640")).is_err());
+        assert!(parse(&args("decode in.fic out.png --size 0x480")).is_err());
+        assert!(parse(&args("serve --iterations 3")).is_err());
+    }
+
+    #[test]
+    fn serve_listens_locally_by_default() {
+        assert_eq!(parse(&args("serve")).unwrap(), Command::Serve { addr: DEFAULT_ADDR.to_string() });
+        assert_eq!(parse(&args("serve 0.0.0.0:80")).unwrap(), Command::Serve { addr: "0.0.0.0:80".to_string() });
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert!(parse(&args("")).is_err());
+        assert!(parse(&args("squash in.png")).is_err());
+        assert!(parse(&args("encode in.png")).is_err());
+        assert!(parse(&args("encode in.png out.fic --shift-bits")).is_err());
+        assert!(parse(&args("encode in.png out.fic --shift-bits many")).is_err());
+        assert!(parse(&args("encode in.png out.fic --big-square-size 10 --small-square-size 4")).is_err());
+        assert!(parse(&args("encode in.png out.fic --big-square-size 0")).is_err());
+        assert!(parse(&args("encode in.png out.fic --big-square-size 4 --small-square-size 8")).is_err());
+        assert!(parse(&args("encode in.png out.fic --small-square-size 0")).is_err());
+        assert!(parse(&args("encode in.png out.fic --search nearest:0")).is_err());
+        assert!(parse(&args("encode in.png out.fic --search local")).is_err());
+        assert!(parse(&args("encode in.png out.fic --accept-mse -1")).is_err());
+        assert!(parse(&args("encode in.png out.fic --small-square-size 4 --split-depth 3")).is_err());
+        assert!(parse(&args("encode in.png out.fic --partition hv --big-square-size 8 --small-square-size 8")).is_err());
+        assert!(parse(&args("encode in.png out.fic --partition bsp")).is_err());
+        assert!(parse(&args("encode in.png out.fic --partition hv --domain-step 3")).is_err());
+        assert!(parse(&args("roundtrip in.png --partition hv --big-square-size 9 --small-square-size 3")).is_err());
+        assert!(parse(&args("encode in.png out.fic --target-bpp 0")).is_err());
+        assert!(parse(&args("encode in.png out.fic --target-psnr inf")).is_err());
+        assert!(parse(&args("encode in.png out.fic --factor-bits 3 --precision-levels 3")).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use std::io;
+
+use image::{DynamicImage, GenericImageView, RgbImage};
+
+use channel::{self, RgbPx};
+use container;
+use domain_pool::SearchStats;
+use rd::RateDistortion;
+use fractal::{self, CompSettings, Compressed, DecompSettings};
+
+fn to_rgb_pixels(img: &DynamicImage) -> Vec<Vec<RgbPx>> {
+    let (width, height) = img.dimensions();
+    (0..height)
+        .map(|y|
+            (0..width)
+                .map(|x| img.get_pixel(x, y).0)
+                .map(|px| RgbPx { r: px[0], g: px[1], b: px[2] })
+                .collect())
+        .collect()
+}
+
+/// Compresses the R, G and B channels of the image independently.
+pub fn compress_image(img: &DynamicImage, settings: CompSettings) -> io::Result<Vec<Compressed>> {
+    compress_image_with_stats(img, settings).map(|(channels, _, _)| channels)
+}
+
+/// `compress_image`, also telling how much searching all channels took and,
+/// with a `target`, what every channel is expected to come to.
+pub fn compress_image_with_stats(img: &DynamicImage, settings: CompSettings) -> io::Result<(Vec<Compressed>, SearchStats, Vec<RateDistortion>)> {
+    let (rs, gs, bs) = channel::to_rgb_channels(&to_rgb_pixels(img));
+    // every channel counts the stream header as its own
+    let shared_bpp = container::STREAM_HEADER_BITS as f32 / (img.width() * img.height()) as f32;
+    let settings = CompSettings { target: settings.target.map(|t| t.per_channel(3, shared_bpp)), ..settings };
+    let mut total = SearchStats::default();
+    let mut estimates = Vec::new();
+    let channels = [rs, gs, bs].iter()
+        .map(|channel| {
+            let (comp, stats, estimate) = fractal::compress_with_stats(channel, settings)?;
+            total += stats;
+            estimates.extend(estimate);
+            Ok(comp)
+        })
+        .collect::<io::Result<_>>()?;
+    Ok((channels, total, estimates))
+}
+
+/// Decompresses either three R, G, B channels or a single grey one.
+pub fn decompress_image(channels: &[Compressed], settings: DecompSettings) -> io::Result<RgbImage> {
+    decompress_image_with_stats(channels, settings).map(|(img, _)| img)
+}
+
+/// `decompress_image`, also telling how many passes the slowest channel took.
+pub fn decompress_image_with_stats(channels: &[Compressed], settings: DecompSettings) -> io::Result<(RgbImage, usize)> {
+    if channels.len() != 1 && channels.len() != 3 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("expected 1 or 3 channels, got {}", channels.len())));
+    }
+    let (decoded, passes): (Vec<_>, Vec<_>) = channels.iter()
+        .map(|c| fractal::decompress_with_stats(c, settings))
+        .unzip();
+    let (width, height) = settings.size.of(channels[0].orig_width, channels[0].orig_height);
+    let (width, height) = (width as u32, height as u32);
+    if decoded.iter().any(|ch| ch.len() != height as usize || ch[0].len() != width as usize) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "channels differ in size"));
+    }
+    let last = decoded.len() - 1;
+    let img = RgbImage::from_fn(width, height, |x_i, y_i| {
+        let (x, y) = (x_i as usize, y_i as usize);
+        image::Rgb([decoded[0][y][x], decoded[last.min(1)][y][x], decoded[last][y][x]])
+    });
+    Ok((img, passes.into_iter().max().unwrap_or(0)))
+}
+
+/// Peak signal-to-noise ratio in dB over all three channels.
+pub fn psnr(a: &RgbImage, b: &RgbImage) -> f64 {
+    assert_eq!(a.dimensions(), b.dimensions(), "can't compare images of different size");
+    let sqr_sum = a.as_raw().iter()
+        .zip(b.as_raw().iter())
+        .map(|(&p, &q)| (p as i64 - q as i64) * (p as i64 - q as i64))
+        .sum::<i64>();
+    if sqr_sum == 0 {
+        return f64::INFINITY;
+    }
+    let mse = sqr_sum as f64 / a.as_raw().len() as f64;
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use codec::*;
+    use rd::Target;
+    use testing::noise_at;
+
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(12, 10, |x, y| {
+            image::Rgb([(x * 20) as u8, (y * 25) as u8, ((x + y) * 10) as u8])
+        }))
+    }
+
+    #[test]
+    fn decompressed_image_has_original_size() {
+        let settings = CompSettings { big_square_size: 8, small_square_size: 4, grouping_factor: 1, ..CompSettings::default() };
+        let channels = compress_image(&gradient(), settings).unwrap();
+        assert_eq!(channels.len(), 3);
+        let restored = decompress_image(&channels, DecompSettings { iterations: 8, ..DecompSettings::default() }).unwrap();
+        assert_eq!(restored.dimensions(), (12, 10));
+        assert!(psnr(&gradient().to_rgb8(), &restored) > 20.0);
+    }
+
+    #[test]
+    fn search_stats_add_up_over_channels() {
+        let settings = CompSettings { big_square_size: 8, small_square_size: 4, grouping_factor: 1, ..CompSettings::default() };
+        let (channels, stats, estimates) = compress_image_with_stats(&gradient(), settings).unwrap();
+        let (rs, gs, bs) = channel::to_rgb_channels(&to_rgb_pixels(&gradient()));
+        let compared = [rs, gs, bs].iter().map(|ch| fractal::compress_with_stats(ch, settings).unwrap().1.compared).sum::<usize>();
+        assert_eq!(channels.len(), 3);
+        assert_eq!(stats.compared, compared);
+        assert!(estimates.is_empty());
+        let (_, _, estimates) = compress_image_with_stats(&gradient(), CompSettings { target: Some(Target::Psnr(30.0)), ..settings }).unwrap();
+        assert_eq!(estimates.len(), 3);
+    }
+
+    #[test]
+    fn written_image_fits_a_reachable_bit_budget() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 48, |x, y| {
+            let (x, y) = (x as usize, y as usize);
+            image::Rgb([noise_at(x, y), noise_at(y, x), noise_at(x + 7, y)])
+        }));
+        let settings = CompSettings { big_square_size: 16, small_square_size: 8, split_depth: 2, precision_levels: 2, ..CompSettings::default() };
+        for &bpp in [2.0, 4.0].iter() {
+            let (channels, _, estimates) = compress_image_with_stats(&img, CompSettings { target: Some(Target::Bpp(bpp)), ..settings }).unwrap();
+            let mut bytes = Vec::new();
+            container::write(&channels, container::Coding::Packed, &mut bytes).unwrap();
+            assert!(bytes.len() * 8 <= (bpp * 64.0 * 48.0) as usize, "{} bytes for {} bpp", bytes.len(), bpp);
+            // the budget is used, not just kept to
+            assert!(estimates.iter().map(|e| e.bits).sum::<usize>() as f32 > 0.8 * bpp * 64.0 * 48.0);
+        }
+    }
+
+    #[test]
+    fn psnr_of_identical_images_is_infinite() {
+        let img = gradient().to_rgb8();
+        assert_eq!(psnr(&img, &img), f64::INFINITY);
+    }
+
+    #[test]
+    fn psnr_of_off_by_one_images() {
+        let a = RgbImage::from_pixel(4, 4, image::Rgb([10, 10, 10]));
+        let b = RgbImage::from_pixel(4, 4, image::Rgb([11, 11, 11]));
+        assert!((psnr(&a, &b) - 48.13).abs() < 0.01);
+    }
+}
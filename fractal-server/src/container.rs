@@ -0,0 +1,976 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use bits::*;
+use byte_rect::*;
+use fractal::*;
+use partition::{self, can_cut, Partition, Split, MIN_HV_SIDE};
+use range_coder::{Decoder, Encoder, ValueModel};
+
+pub const MAGIC: &[u8; 4] = b"FRAC";
+pub const VERSION: u8 = 11;
+/// Magic, version, mapping coding and channel count.
+pub(crate) const STREAM_HEADER_BITS: usize = (MAGIC.len() + 3) * 8;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn write_u8<W: Write>(out: &mut W, v: u8) -> io::Result<()> {
+    out.write_all(&[v])
+}
+
+fn write_u32<W: Write>(out: &mut W, v: usize) -> io::Result<()> {
+    if v > u32::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "value does not fit into u32"));
+    }
+    out.write_all(&(v as u32).to_le_bytes())
+}
+
+fn read_u8<R: Read>(input: &mut R) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    input.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<usize> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf) as usize)
+}
+
+fn read_f32<R: Read>(input: &mut R) -> io::Result<f32> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    Ok(f32::from_le_bytes(buf))
+}
+
+fn write_settings<W: Write>(out: &mut W, settings: CompSettings) -> io::Result<()> {
+    write_u32(out, settings.big_square_size)?;
+    write_u32(out, settings.small_square_size)?;
+    write_u8(out, settings.factor_bits)?;
+    write_u8(out, settings.shift_bits)?;
+    out.write_all(&settings.max_factor.to_le_bytes())?;
+    write_u32(out, settings.split_depth)?;
+    write_u8(out, settings.partition as u8)?;
+    write_u32(out, settings.domain_step)?;
+    write_u8(out, settings.precision_levels)
+}
+
+fn read_settings<R: Read>(input: &mut R) -> io::Result<CompSettings> {
+    let settings = CompSettings {
+        big_square_size: read_u32(input)?,
+        small_square_size: read_u32(input)?,
+        factor_bits: read_u8(input)?,
+        shift_bits: read_u8(input)?,
+        max_factor: read_f32(input)?,
+        split_depth: read_u32(input)?,
+        partition: match read_u8(input)? {
+            0 => Partition::Quadtree,
+            1 => Partition::Hv,
+            _ => return Err(invalid("unknown partition")),
+        },
+        domain_step: read_u32(input)?,
+        precision_levels: read_u8(input)?,
+        ..CompSettings::default()
+    };
+    if settings.factor_bits > 24 || settings.shift_bits > 24 {
+        return Err(invalid("coefficient bit depth is out of range"));
+    }
+    if !(settings.max_factor > 0.0 && settings.max_factor.is_finite()) {
+        return Err(invalid("factor limit is out of range"));
+    }
+    if settings.precision_levels > 0 && settings.precision_levels >= settings.factor_bits.min(settings.shift_bits) {
+        return Err(invalid("coefficient precision levels are out of range"));
+    }
+    if settings.split_depth > partition::MAX_SPLIT_DEPTH {
+        return Err(invalid("split depth is out of range"));
+    }
+    match settings.partition {
+        Partition::Quadtree => {
+            // both block sizes have to stay whole at every level
+            let whole = |size: usize| settings.split_depth < usize::BITS as usize && size.is_multiple_of(1 << settings.split_depth);
+            if !whole(settings.small_square_size) || !whole(settings.big_square_size) {
+                return Err(invalid("split depth is out of range"));
+            }
+        }
+        Partition::Hv => {
+            if settings.big_square_size < 2 * settings.small_square_size {
+                return Err(invalid("no domain is twice the size of a range block"));
+            }
+            if !settings.big_square_size.is_multiple_of(2) {
+                return Err(invalid("odd HV big square size"));
+            }
+            if !settings.domain_step.is_multiple_of(2) {
+                return Err(invalid("odd HV domain step"));
+            }
+        }
+    }
+    Ok(settings)
+}
+
+const TRANSFORM_BITS: u8 = 3;
+
+/// How the mapping of every channel is turned into bytes.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Coding {
+    /// Every field is written with a fixed number of bits.
+    Packed,
+    /// Fields go through an adaptive range coder with a separate model per field.
+    Range,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+enum Field {
+    Split,
+    CutAxis,
+    /// Cut positions stored with the given number of bits.
+    CutAt(u8),
+    /// Domain addresses stored with the given number of bits.
+    Domain(u8),
+    Transform,
+    Coarseness,
+    /// Coefficients stored with the given number of bits.
+    Factor(u8),
+    Shift(u8),
+}
+
+trait SymbolWriter {
+    fn put(&mut self, field: Field, value: u32, bits: u8);
+    fn finish(self) -> Vec<u8>;
+}
+
+trait SymbolReader {
+    fn take(&mut self, field: Field, bits: u8) -> io::Result<u32>;
+}
+
+impl SymbolWriter for BitWriter {
+    fn put(&mut self, _: Field, value: u32, bits: u8) {
+        self.write(value, bits)
+    }
+
+    fn finish(self) -> Vec<u8> {
+        BitWriter::finish(self)
+    }
+}
+
+/// Counts the bits `Coding::Packed` takes.
+struct BitCount(usize);
+
+impl SymbolWriter for BitCount {
+    fn put(&mut self, _: Field, _: u32, bits: u8) {
+        self.0 += bits as usize
+    }
+
+    fn finish(self) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+impl<'a> SymbolReader for BitReader<'a> {
+    fn take(&mut self, _: Field, bits: u8) -> io::Result<u32> {
+        self.read(bits)
+    }
+}
+
+fn model_for(models: &mut HashMap<Field, ValueModel>, field: Field, bits: u8) -> &mut ValueModel {
+    let model = models.entry(field).or_insert_with(|| ValueModel::new(bits));
+    assert_eq!(model.bits(), bits, "bit width of {:?} changed within a channel", field);
+    model
+}
+
+struct ModelWriter {
+    enc: Encoder,
+    models: HashMap<Field, ValueModel>,
+}
+
+impl SymbolWriter for ModelWriter {
+    fn put(&mut self, field: Field, value: u32, bits: u8) {
+        model_for(&mut self.models, field, bits).encode(&mut self.enc, value)
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.enc.finish()
+    }
+}
+
+struct ModelReader<'a> {
+    dec: Decoder<'a>,
+    models: HashMap<Field, ValueModel>,
+}
+
+impl<'a> SymbolReader for ModelReader<'a> {
+    fn take(&mut self, field: Field, bits: u8) -> io::Result<u32> {
+        Ok(model_for(&mut self.models, field, bits).decode(&mut self.dec))
+    }
+}
+
+/// Longest side of a padded image `read` accepts.
+const MAX_SIDE: usize = 1 << 16;
+
+/// How big a channel `read_within` accepts, so that a few bytes of stream
+/// can't make reading or decoding allocate without bound.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Limits {
+    /// Most pixels of the padded image, which the decoder allocates a few
+    /// times over.
+    pub area: usize,
+    /// Most range blocks.
+    pub range_blocks: usize,
+}
+
+impl Default for Limits {
+    /// What `read` accepts and `write` produces.
+    fn default() -> Limits {
+        Limits { area: 1 << 26, range_blocks: 1 << 22 }
+    }
+}
+
+impl Limits {
+    /// Whether a padded image is beyond the limits.
+    fn too_big(&self, padded_width: usize, padded_height: usize) -> bool {
+        padded_width > MAX_SIDE || padded_height > MAX_SIDE || padded_width * padded_height > self.area
+    }
+}
+
+/// Header value of `DomainCoder::window` for domains stored as grid indices.
+const ABSOLUTE: usize = u32::MAX as usize;
+
+/// How big rectangles are addressed: by their index on the whole lattice
+/// of domains of their size, or, when the mapping only refers to domains
+/// close to the range blocks, by their offset from the lattice cell the range
+/// block lies in.
+#[derive(Debug, Clone, Copy)]
+struct DomainCoder {
+    width: usize,
+    height: usize,
+    /// Distance between neighbouring domains on either axis.
+    step_x: usize,
+    step_y: usize,
+    cols: usize,
+    rows: usize,
+    /// Largest offset in cells on either axis, or `ABSOLUTE`.
+    window: usize,
+}
+
+impl DomainCoder {
+    /// Addressing of the `width x height` domains on the lattice of `step`,
+    /// see `lattice_step`.
+    fn new(padded_width: usize, padded_height: usize, width: usize, height: usize, step: usize, window: usize) -> DomainCoder {
+        let (step_x, step_y) = (lattice_step(step, width), lattice_step(step, height));
+        let count = |len: usize, side: usize, step: usize| if side <= len { (len - side) / step + 1 } else { 0 };
+        DomainCoder {
+            width,
+            height,
+            step_x,
+            step_y,
+            cols: count(padded_width, width, step_x),
+            rows: count(padded_height, height, step_y),
+            window,
+        }
+    }
+
+    /// The cheaper addressing for the range blocks of a quadtree partitioned
+    /// `comp` that have been split `level` times, or `None` if a big square
+    /// is off their domain lattice.
+    fn choose(comp: &Compressed, level: usize) -> Option<DomainCoder> {
+        let side = comp.settings.big_square_size >> level;
+        let absolute = DomainCoder::new(comp.padded_width, comp.padded_height, side, side, comp.settings.domain_step, ABSOLUTE);
+        let mut window = 0;
+        for map in comp.mapping.iter().filter(|map| level_of(&comp.settings, map.small) == level) {
+            if !absolute.fits(map.big) {
+                return None;
+            }
+            let dx = (map.big.x / absolute.step_x).abs_diff(map.small.x / absolute.step_x);
+            let dy = (map.big.y / absolute.step_y).abs_diff(map.small.y / absolute.step_y);
+            window = window.max(dx).max(dy);
+        }
+        let relative = DomainCoder { window, ..absolute };
+        Some(if relative.bits() < absolute.bits() { relative } else { absolute })
+    }
+
+    /// Whether `big` is on the lattice.
+    fn fits(&self, big: RectCoords) -> bool {
+        big.width == self.width && big.height == self.height
+            && big.x.is_multiple_of(self.step_x) && big.y.is_multiple_of(self.step_y)
+            && big.x / self.step_x < self.cols && big.y / self.step_y < self.rows
+    }
+
+    fn bits(&self) -> u8 {
+        if self.window == ABSOLUTE {
+            bits_for(self.cols.saturating_mul(self.rows))
+        } else {
+            let width = self.window.saturating_mul(2).saturating_add(1);
+            bits_for(width.saturating_mul(width))
+        }
+    }
+
+    fn encode(&self, small: RectCoords, big: RectCoords) -> u32 {
+        let (x, y) = (big.x / self.step_x, big.y / self.step_y);
+        if self.window == ABSOLUTE {
+            return (y * self.cols + x) as u32;
+        }
+        let width = 2 * self.window + 1;
+        let (dx, dy) = (x + self.window - small.x / self.step_x, y + self.window - small.y / self.step_y);
+        (dy * width + dx) as u32
+    }
+
+    fn decode(&self, small: RectCoords, value: u32) -> io::Result<RectCoords> {
+        let value = value as usize;
+        let (x, y) = if self.window == ABSOLUTE {
+            if value >= self.cols * self.rows {
+                return Err(invalid("big square is out of range"));
+            }
+            (value % self.cols, value / self.cols)
+        } else {
+            let width = 2 * self.window + 1;
+            // may wrap around for offsets past the left or top edge, which the bounds check catches
+            ((small.x / self.step_x + value % width).wrapping_sub(self.window),
+             (small.y / self.step_y + value / width).wrapping_sub(self.window))
+        };
+        if x >= self.cols || y >= self.rows {
+            return Err(invalid("big square is out of range"));
+        }
+        Ok(RectCoords { x: x * self.step_x, y: y * self.step_y, width: self.width, height: self.height })
+    }
+}
+
+/// How many times the quadtree range block `small` has been split.
+fn level_of(settings: &CompSettings, small: RectCoords) -> usize {
+    settings.small_square_size.checked_div(small.width).unwrap_or(0).trailing_zeros() as usize
+}
+
+/// The domain addressing of every range block of a channel.
+enum DomainCoders {
+    /// One per quadtree level, with the window stored in the header.
+    Levels(Vec<DomainCoder>),
+    /// HV domains are always stored as grid indices.
+    Hv { padded_width: usize, padded_height: usize },
+}
+
+impl DomainCoders {
+    fn for_block(&self, settings: &CompSettings, small: RectCoords) -> DomainCoder {
+        match *self {
+            DomainCoders::Levels(ref levels) => levels[level_of(settings, small)],
+            DomainCoders::Hv { padded_width, padded_height } => DomainCoder::new(
+                padded_width, padded_height, 2 * small.width, 2 * small.height, settings.domain_step, ABSOLUTE),
+        }
+    }
+}
+
+/// Stores `split` of `node`, which the partition could have split.
+fn put_split<S: SymbolWriter>(sink: &mut S, node: RectCoords, split: Split) {
+    sink.put(Field::Split, (split != Split::Leaf) as u32, 1);
+    let (vertical, at, side) = match split {
+        Split::Vertical(at) => (true, at, node.width),
+        Split::Horizontal(at) => (false, at, node.height),
+        _ => return,
+    };
+    if can_cut(node.width) && can_cut(node.height) {
+        sink.put(Field::CutAxis, !vertical as u32, 1);
+    }
+    let bits = bits_for(side - 2 * MIN_HV_SIDE + 1);
+    sink.put(Field::CutAt(bits), (at - MIN_HV_SIDE) as u32, bits);
+}
+
+fn take_split<S: SymbolReader>(source: &mut S, scheme: Partition, node: RectCoords, depth: usize) -> io::Result<Split> {
+    if source.take(Field::Split, 1)? == 0 {
+        return Ok(Split::Leaf);
+    }
+    if scheme == Partition::Quadtree {
+        return Ok(Split::Quarters);
+    }
+    let vertical = if can_cut(node.width) && can_cut(node.height) {
+        source.take(Field::CutAxis, 1)? == 0
+    } else {
+        can_cut(node.width)
+    };
+    let side = if vertical { node.width } else { node.height };
+    let bits = bits_for(side - 2 * MIN_HV_SIDE + 1);
+    let at = source.take(Field::CutAt(bits), bits)? as usize + MIN_HV_SIDE;
+    let split = if vertical { Split::Vertical(at) } else { Split::Horizontal(at) };
+    if !scheme.allows(node, depth, split) {
+        return Err(invalid("cut is out of range"));
+    }
+    Ok(split)
+}
+
+/// Serializes the partition and the mapping. The splits of the nodes that
+/// could be split come first, in pre-order. Then the range blocks, which
+/// are implied by the partition, get their fields one after the other: the
+/// big rectangle addressed through `domains`, the transform, the coarseness
+/// if the settings allow more than one, and the quantization indices of the
+/// coefficients at that precision.
+fn pack_mapping<S: SymbolWriter>(comp: &Compressed, domains: &DomainCoders, mut sink: S) -> io::Result<Vec<u8>> {
+    let settings = &comp.settings;
+    let small_grid = square_grid(comp.padded_width, comp.padded_height, settings.small_square_size);
+    let unpackable = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    let mut splits = comp.partition.iter();
+    let leaves = partition::leaves(&small_grid, settings.split_depth, |node, depth| {
+        let split = *splits.next().ok_or_else(|| unpackable("partition is truncated"))?;
+        if !settings.partition.allows(node, depth, split) {
+            return Err(unpackable("partition splits a block it may not"));
+        }
+        if settings.partition.can_split(node, depth) {
+            put_split(&mut sink, node, split);
+        }
+        Ok(split)
+    })?;
+    if splits.next().is_some() {
+        return Err(unpackable("partition is too long"));
+    }
+    if comp.mapping.len() != leaves.len() {
+        return Err(unpackable("mapping does not cover the range grid"));
+    }
+    for (map, &small) in comp.mapping.iter().zip(leaves.iter()) {
+        if map.small != small {
+            return Err(unpackable("mapping is not in range grid order"));
+        }
+        if map.trans.transposes() && !small.is_square() {
+            return Err(unpackable("transform does not keep the shape of the block"));
+        }
+        if map.coarseness > settings.precision_levels {
+            return Err(unpackable("coefficient precision is out of range"));
+        }
+        let coder = domains.for_block(settings, small);
+        if !coder.fits(map.big) {
+            return Err(unpackable("big square is not on the domain grid"));
+        }
+        put_block(&mut sink, settings, &coder, map);
+    }
+    Ok(sink.finish())
+}
+
+/// Bits of the coarseness of every range block, if there is a choice.
+fn coarseness_bits(settings: &CompSettings) -> Option<u8> {
+    if settings.precision_levels > 0 { Some(bits_for(settings.precision_levels as usize + 1)) } else { None }
+}
+
+/// Stores the fields of the range block of `map`, whose domain `coder` fits.
+fn put_block<S: SymbolWriter>(sink: &mut S, settings: &CompSettings, coder: &DomainCoder, map: &SquareMapping) {
+    let quantizer = settings.coarse_quantizer(map.coarseness);
+    let trans_i = Transform::ALL.iter().position(|&t| t == map.trans).unwrap();
+    let (factor_q, shift_q) = quantizer.quantize(map.coeffs);
+    sink.put(Field::Domain(coder.bits()), coder.encode(map.small, map.big), coder.bits());
+    sink.put(Field::Transform, trans_i as u32, TRANSFORM_BITS);
+    if let Some(bits) = coarseness_bits(settings) {
+        sink.put(Field::Coarseness, map.coarseness as u32, bits);
+    }
+    sink.put(Field::Factor(quantizer.factor_bits), factor_q, quantizer.factor_bits);
+    sink.put(Field::Shift(quantizer.shift_bits), shift_q, quantizer.shift_bits);
+}
+
+/// Bits `Coding::Packed` takes for storing `split` of `node`, which the
+/// partition could have split.
+pub(crate) fn split_bits(node: RectCoords, split: Split) -> usize {
+    let mut count = BitCount(0);
+    put_split(&mut count, node, split);
+    count.0
+}
+
+/// Bits `Coding::Packed` takes for the range block of `map` in a channel of
+/// `padded_width x padded_height`, with its domain stored as a lattice index.
+pub(crate) fn block_bits(settings: &CompSettings, padded_width: usize, padded_height: usize, map: &SquareMapping) -> usize {
+    let coder = DomainCoder::new(padded_width, padded_height, map.big.width, map.big.height, settings.domain_step, ABSOLUTE);
+    let mut count = BitCount(0);
+    put_block(&mut count, settings, &coder, map);
+    count.0
+}
+
+/// Bits a stream of a single channel with these settings takes besides the
+/// mapping: the headers, and the last byte of the mapping, which may be
+/// partly unused.
+pub(crate) fn overhead_bits(settings: &CompSettings) -> usize {
+    let mut stored = Vec::new();
+    write_settings(&mut stored, *settings).expect("writing to memory failed");
+    let windows = match settings.partition {
+        Partition::Quadtree => 4 * (settings.split_depth + 1),
+        Partition::Hv => 0,
+    };
+    // dimensions, settings, domain windows and mapping length
+    STREAM_HEADER_BITS + (4 * 4 + stored.len() + windows + 4) * 8 + 7
+}
+
+fn unpack_mapping<S: SymbolReader>(mut source: S, padded_width: usize, padded_height: usize, settings: CompSettings, domains: &DomainCoders, limits: Limits) -> io::Result<(Vec<Split>, Vec<SquareMapping>)> {
+    let small_grid = square_grid(padded_width, padded_height, settings.small_square_size);
+    let mut splits = Vec::new();
+    let mut leaf_count = 0;
+    let leaves = partition::leaves(&small_grid, settings.split_depth, |node, depth| {
+        let split = if settings.partition.can_split(node, depth) {
+            take_split(&mut source, settings.partition, node, depth)?
+        } else {
+            Split::Leaf
+        };
+        if split == Split::Leaf {
+            leaf_count += 1;
+            if leaf_count > limits.range_blocks {
+                return Err(invalid("too many range blocks"));
+            }
+        }
+        splits.push(split);
+        Ok::<_, io::Error>(split)
+    })?;
+    let mapping = leaves
+        .into_iter()
+        .map(|small| {
+            let coder = domains.for_block(&settings, small);
+            let big = coder.decode(small, source.take(Field::Domain(coder.bits()), coder.bits())?)?;
+            let trans = *Transform::ALL.get(source.take(Field::Transform, TRANSFORM_BITS)? as usize)
+                .ok_or_else(|| invalid("unknown transform"))?;
+            if trans.transposes() && !small.is_square() {
+                return Err(invalid("transform does not keep the shape of the block"));
+            }
+            let coarseness = match coarseness_bits(&settings) {
+                Some(bits) => source.take(Field::Coarseness, bits)? as u8,
+                None => 0,
+            };
+            if coarseness > settings.precision_levels {
+                return Err(invalid("coefficient precision is out of range"));
+            }
+            let quantizer = settings.coarse_quantizer(coarseness);
+            let factor_q = source.take(Field::Factor(quantizer.factor_bits), quantizer.factor_bits)?;
+            let shift_q = source.take(Field::Shift(quantizer.shift_bits), quantizer.shift_bits)?;
+            Ok(SquareMapping { small, big, trans, coeffs: quantizer.dequantize(factor_q, shift_q), coarseness })
+        })
+        .collect::<io::Result<_>>()?;
+    Ok((splits, mapping))
+}
+
+fn write_channel<W: Write>(out: &mut W, comp: &Compressed, coding: Coding) -> io::Result<()> {
+    // nothing `read` would reject
+    let limits = Limits::default();
+    if limits.too_big(comp.padded_width, comp.padded_height) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "image is too big to store"));
+    }
+    if comp.mapping.len() > limits.range_blocks {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many range blocks to store"));
+    }
+    write_u32(out, comp.orig_width)?;
+    write_u32(out, comp.orig_height)?;
+    write_u32(out, comp.padded_width)?;
+    write_u32(out, comp.padded_height)?;
+    write_settings(out, comp.settings)?;
+    let domains = match comp.settings.partition {
+        Partition::Quadtree => {
+            let levels = (0..=comp.settings.split_depth)
+                .map(|level| DomainCoder::choose(comp, level))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "big square is not on the domain grid"))?;
+            for level in levels.iter() {
+                write_u32(out, level.window)?;
+            }
+            DomainCoders::Levels(levels)
+        }
+        Partition::Hv => DomainCoders::Hv { padded_width: comp.padded_width, padded_height: comp.padded_height },
+    };
+    let packed = match coding {
+        Coding::Packed => pack_mapping(comp, &domains, BitWriter::new())?,
+        Coding::Range => pack_mapping(comp, &domains, ModelWriter { enc: Encoder::new(), models: Default::default() })?,
+    };
+    write_u32(out, packed.len())?;
+    out.write_all(&packed)
+}
+
+fn read_channel<R: Read>(input: &mut R, coding: Coding, limits: Limits) -> io::Result<Compressed> {
+    let orig_width = read_u32(input)?;
+    let orig_height = read_u32(input)?;
+    let padded_width = read_u32(input)?;
+    let padded_height = read_u32(input)?;
+    let settings = read_settings(input)?;
+    if settings.big_square_size == 0 || settings.small_square_size == 0 {
+        return Err(invalid("zero block size"));
+    }
+    if orig_width == 0 || orig_height == 0 {
+        return Err(invalid("empty image"));
+    }
+    if orig_width > padded_width || orig_height > padded_height {
+        return Err(invalid("image is bigger than its padding"));
+    }
+    if limits.too_big(padded_width, padded_height) {
+        return Err(invalid("image is too big"));
+    }
+    // the encoder pads to whole big squares and no further
+    let padded = |side: usize| side.div_ceil(settings.big_square_size) * settings.big_square_size;
+    if padded_width != padded(orig_width) || padded_height != padded(orig_height)
+        || !padded_width.is_multiple_of(settings.small_square_size) || !padded_height.is_multiple_of(settings.small_square_size) {
+        return Err(invalid("padding does not fit the blocks"));
+    }
+    if (padded_width / settings.small_square_size) * (padded_height / settings.small_square_size) > limits.range_blocks {
+        return Err(invalid("too many range blocks"));
+    }
+    let domains = match settings.partition {
+        Partition::Quadtree => DomainCoders::Levels((0..=settings.split_depth)
+            .map(|level| {
+                let window = read_u32(input)?;
+                if window != ABSOLUTE && window > padded_width.max(padded_height) {
+                    return Err(invalid("domain window is out of range"));
+                }
+                let side = settings.big_square_size >> level;
+                Ok(DomainCoder::new(padded_width, padded_height, side, side, settings.domain_step, window))
+            })
+            .collect::<io::Result<Vec<_>>>()?),
+        Partition::Hv => DomainCoders::Hv { padded_width, padded_height },
+    };
+    // no lattice of HV domains is bigger than the image
+    let widest = match domains {
+        DomainCoders::Levels(ref levels) => levels.iter().map(|coder| coder.bits()).max().unwrap_or(0),
+        DomainCoders::Hv { .. } => bits_for(padded_width.saturating_mul(padded_height)),
+    };
+    if widest > 32 {
+        return Err(invalid("domain index is too wide"));
+    }
+    let packed_len = read_u32(input)?;
+    let mut packed = Vec::new();
+    input.take(packed_len as u64).read_to_end(&mut packed)?;
+    if packed.len() != packed_len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "mapping is truncated"));
+    }
+    let (partition, mapping) = match coding {
+        Coding::Packed => unpack_mapping(BitReader::new(&packed), padded_width, padded_height, settings, &domains, limits)?,
+        Coding::Range => {
+            let reader = ModelReader { dec: Decoder::new(&packed), models: Default::default() };
+            unpack_mapping(reader, padded_width, padded_height, settings, &domains, limits)?
+        }
+    };
+    Ok(Compressed { orig_width, orig_height, padded_width, padded_height, settings, partition, mapping })
+}
+
+/// Writes the channels (usually R, G and B) of one image as a single stream:
+/// magic, format version, mapping coding, channel count and then every
+/// channel in order.
+pub fn write<W: Write>(channels: &[Compressed], coding: Coding, out: &mut W) -> io::Result<()> {
+    if channels.len() > u8::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many channels"));
+    }
+    out.write_all(MAGIC)?;
+    write_u8(out, VERSION)?;
+    write_u8(out, coding as u8)?;
+    write_u8(out, channels.len() as u8)?;
+    for comp in channels.iter() {
+        write_channel(out, comp, coding)?;
+    }
+    Ok(())
+}
+
+pub fn read<R: Read>(input: &mut R) -> io::Result<Vec<Compressed>> {
+    read_within(input, Limits::default())
+}
+
+/// `read`, failing on channels bigger than `limits` before allocating
+/// anything for them.
+pub fn read_within<R: Read>(input: &mut R, limits: Limits) -> io::Result<Vec<Compressed>> {
+    let mut magic = [0; 4];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a fractal image stream"));
+    }
+    let version = read_u8(input)?;
+    if version != VERSION {
+        return Err(invalid(&format!("unsupported format version {}", version)));
+    }
+    let coding = match read_u8(input)? {
+        0 => Coding::Packed,
+        1 => Coding::Range,
+        _ => return Err(invalid("unknown mapping coding")),
+    };
+    let channel_count = read_u8(input)?;
+    (0..channel_count).map(|_| read_channel(input, coding, limits)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use container::*;
+    use rd::Target;
+    use search::Search;
+    use std::io;
+    use testing::{noise, noise_at, picture};
+
+    fn sample() -> Compressed {
+        let picture = vec![
+            vec![11, 12, 13, 14, 15,],
+            vec![21, 22, 23, 24, 25,],
+            vec![31, 32, 33, 34, 35,],
+            vec![41, 42, 43, 44, 45,],
+            vec![51, 52, 53, 54, 55,],
+        ];
+        compress(&picture, CompSettings {
+            big_square_size: 4,
+            small_square_size: 2,
+            grouping_factor: 1,
+            ..CompSettings::default()
+        }).unwrap()
+    }
+
+    /// `comp` written with the header fields at these byte offsets changed.
+    fn tampered(comp: Compressed, fields: &[(usize, u32)]) -> io::Result<Vec<Compressed>> {
+        let mut bytes = Vec::new();
+        write(&[comp], Coding::Packed, &mut bytes).unwrap();
+        for &(offset, value) in fields.iter() {
+            bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        read(&mut &bytes[..])
+    }
+
+    const ORIG_WIDTH: usize = 7;
+    const ORIG_HEIGHT: usize = 11;
+    const PADDED_WIDTH: usize = 15;
+    const PADDED_HEIGHT: usize = 19;
+    const BIG_SQUARE_SIZE: usize = 23;
+    const SMALL_SQUARE_SIZE: usize = 27;
+    const SPLIT_DEPTH: usize = 37;
+
+    fn write_and_read(channels: &[Compressed], coding: Coding) -> Vec<Compressed> {
+        let mut bytes = Vec::new();
+        write(channels, coding, &mut bytes).unwrap();
+        read(&mut &bytes[..]).unwrap()
+    }
+
+    /// Fails unless `comp` comes back unchanged with either coding.
+    fn assert_round_trip(comp: &Compressed) {
+        for &coding in [Coding::Packed, Coding::Range].iter() {
+            assert_eq!(write_and_read(std::slice::from_ref(comp), coding), std::slice::from_ref(comp), "{:?}", coding);
+        }
+    }
+
+    #[test]
+    fn read_restores_what_was_written() {
+        let channels = vec![sample(), sample()];
+        assert_eq!(write_and_read(&channels, Coding::Packed), channels);
+        assert_eq!(write_and_read(&channels, Coding::Range), channels);
+    }
+
+    #[test]
+    fn mapping_takes_a_fixed_number_of_bits_per_range_block() {
+        let comp = sample();
+        let mut bytes = Vec::new();
+        write(std::slice::from_ref(&comp), Coding::Packed, &mut bytes).unwrap();
+        let header_len = 4 + 1 + 1 + 1 + 4 * 4 + 2 * 4 + 2 + 4 + 4 + 1 + 4 + 1 + 4 + 4;
+        // 4 domains take 2 bits, an offset within a 3x3 window would take 4,
+        // then 3 + 5 + 7 bits for transform and coefficients
+        let mapping_bits = comp.mapping.len() * (2 + 3 + 5 + 7);
+        assert_eq!(bytes.len(), header_len + mapping_bits.div_ceil(8));
+    }
+
+    #[test]
+    fn range_coding_beats_fixed_width_on_repetitive_mapping() {
+        let picture = picture(64, 64, |x, y| if (x / 8 + y / 8) % 2 == 0 { 40 } else { 200 });
+        let comp = compress(&picture, CompSettings {
+            big_square_size: 8,
+            small_square_size: 4,
+            grouping_factor: 1,
+            ..CompSettings::default()
+        }).unwrap();
+        let (mut packed, mut ranged) = (Vec::new(), Vec::new());
+        write(std::slice::from_ref(&comp), Coding::Packed, &mut packed).unwrap();
+        write(std::slice::from_ref(&comp), Coding::Range, &mut ranged).unwrap();
+        assert!(ranged.len() * 2 < packed.len(), "range coded {} vs packed {}", ranged.len(), packed.len());
+    }
+
+    #[test]
+    fn nearby_domains_are_stored_as_offsets() {
+        let picture = noise(64, 64).to_rows();
+        let settings = CompSettings { big_square_size: 8, small_square_size: 4, ..CompSettings::default() };
+        let local = compress(&picture, CompSettings { search: Search::Local { radius: 8, spiral: true }, ..settings }).unwrap();
+        let global = compress(&picture, CompSettings { search: Search::Exhaustive, ..settings }).unwrap();
+        for comp in [&local, &global].iter() {
+            let restored = write_and_read(std::slice::from_ref(comp), Coding::Packed);
+            assert_eq!(restored[0].mapping, comp.mapping);
+        }
+        let size = |comp: &Compressed| {
+            let mut bytes = Vec::new();
+            write(std::slice::from_ref(comp), Coding::Packed, &mut bytes).unwrap();
+            bytes.len()
+        };
+        // 9 of the 64 domains take 4 bits instead of 6
+        assert_eq!(size(&global) - size(&local), (local.mapping.len() * 2).div_ceil(8));
+    }
+
+    #[test]
+    fn quadtree_partition_survives_the_round_trip() {
+        let picture = picture(32, 32, |x, y| if y < 16 { 30 } else { noise_at(x, y) });
+        let comp = compress(&picture, CompSettings {
+            big_square_size: 16,
+            small_square_size: 8,
+            split_depth: 2,
+            split_mse: 20.0,
+            search: Search::Local { radius: 16, spiral: true },
+            ..CompSettings::default()
+        }).unwrap();
+        assert!(comp.mapping.iter().any(|m| m.small.width == 8));
+        assert!(comp.mapping.iter().any(|m| m.small.width == 2));
+        assert_round_trip(&comp);
+    }
+
+    #[test]
+    fn hv_partition_survives_the_round_trip() {
+        let picture = picture(32, 32, |x, y| if x < 11 || y > 21 { 30 } else { noise_at(x, y) });
+        let comp = compress(&picture, CompSettings {
+            big_square_size: 16,
+            small_square_size: 8,
+            partition: Partition::Hv,
+            split_depth: 3,
+            split_mse: 20.0,
+            ..CompSettings::default()
+        }).unwrap();
+        assert!(comp.partition.iter().any(|s| matches!(s, Split::Vertical(_))));
+        assert!(comp.partition.iter().any(|s| matches!(s, Split::Horizontal(_))));
+        assert_round_trip(&comp);
+    }
+
+    #[test]
+    fn overlapping_domains_survive_the_round_trip() {
+        let picture = noise(32, 32).to_rows();
+        let settings = CompSettings { big_square_size: 8, small_square_size: 4, domain_step: 2, split_depth: 1, split_mse: 20.0, ..CompSettings::default() };
+        let spiral = Search::Local { radius: 4, spiral: true };
+        for &settings in [settings, CompSettings { search: spiral, ..settings }, CompSettings { partition: Partition::Hv, ..settings }].iter() {
+            let comp = compress(&picture, settings).unwrap();
+            assert!(comp.mapping.iter().any(|m| !m.big.x.is_multiple_of(m.big.width) || !m.big.y.is_multiple_of(m.big.height)));
+            assert_round_trip(&comp);
+        }
+    }
+
+    #[test]
+    fn target_bitrate_bounds_the_packed_mapping() {
+        let picture = picture(32, 32, |x, y| if y < 16 { 30 } else { ((x * x + 3 * y * y) / 4 % 256) as u8 });
+        let comp = compress(&picture, CompSettings {
+            big_square_size: 16,
+            small_square_size: 8,
+            split_depth: 2,
+            precision_levels: 3,
+            target: Some(Target::Bpp(1.0)),
+            ..CompSettings::default()
+        }).unwrap();
+        assert!(comp.partition.contains(&Split::Quarters));
+        assert!(comp.mapping.iter().any(|m| m.coarseness > 0));
+        let mut bytes = Vec::new();
+        write(std::slice::from_ref(&comp), Coding::Packed, &mut bytes).unwrap();
+        // a window per quadtree level
+        let header_len = 4 + 1 + 1 + 1 + 4 * 4 + 2 * 4 + 2 + 4 + 4 + 1 + 4 + 1 + 3 * 4 + 4;
+        assert!((bytes.len() - header_len) * 8 <= 32 * 32 + 7);
+        assert_round_trip(&comp);
+    }
+
+    #[test]
+    fn write_rejects_transforms_that_do_not_fit_a_rectangle() {
+        let picture = picture(16, 16, |x, y| (x * 7 + y * 13) as u8);
+        let mut comp = compress(&picture, CompSettings {
+            big_square_size: 16,
+            small_square_size: 8,
+            partition: Partition::Hv,
+            split_depth: 1,
+            split_mse: 0.0,
+            ..CompSettings::default()
+        }).unwrap();
+        comp.mapping[0].trans = Transform::HeadToRight;
+        let err = write(&[comp], Coding::Packed, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn write_rejects_images_read_would_reject() {
+        let mut comp = sample();
+        comp.padded_width = MAX_SIDE + 4;
+        let err = write(&[comp], Coding::Packed, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn write_rejects_partition_that_does_not_fit() {
+        let mut comp = sample();
+        comp.partition.push(Split::Leaf);
+        let err = write(&[comp], Coding::Packed, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn write_rejects_mapping_out_of_grid_order() {
+        let mut comp = sample();
+        comp.mapping.swap(0, 1);
+        let err = write(&[comp], Coding::Range, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn read_rejects_foreign_data() {
+        let bytes = b"GIF89a....".to_vec();
+        let err = read(&mut &bytes[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_fails_on_truncated_stream() {
+        let mut bytes = Vec::new();
+        write(&[sample()], Coding::Range, &mut bytes).unwrap();
+        bytes.truncate(bytes.len() - 1);
+        let err = read(&mut &bytes[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn read_rejects_domain_indices_too_wide_to_store() {
+        let huge = 4_000_000_000;
+        let err = tampered(sample(), &[(PADDED_WIDTH, huge), (PADDED_HEIGHT, huge), (BIG_SQUARE_SIZE, 1), (SMALL_SQUARE_SIZE, huge)]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_rejects_dimensions_that_do_not_fit() {
+        let huge = 1 << 17;
+        for fields in [
+            vec![(ORIG_WIDTH, 0)],
+            vec![(ORIG_HEIGHT, 0)],
+            vec![(ORIG_WIDTH, 100), (ORIG_HEIGHT, 100)],
+            vec![(PADDED_WIDTH, 6)],
+            vec![(PADDED_HEIGHT, 0)],
+            vec![(SMALL_SQUARE_SIZE, 3)],
+            vec![(PADDED_WIDTH, huge), (PADDED_HEIGHT, huge)],
+            vec![(PADDED_WIDTH, 12)],
+        ].iter() {
+            let err = tampered(sample(), fields).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", fields);
+        }
+    }
+
+    #[test]
+    fn read_rejects_streams_that_would_exhaust_memory() {
+        let side = 1 << 16;
+        // a single pixel padded to a huge block
+        let padded = tampered(sample(), &[(ORIG_WIDTH, 1), (ORIG_HEIGHT, 1), (PADDED_WIDTH, side), (PADDED_HEIGHT, side),
+            (BIG_SQUARE_SIZE, side), (SMALL_SQUARE_SIZE, side)]);
+        assert_eq!(padded.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // billions of single pixel range blocks
+        let tiny = tampered(sample(), &[(ORIG_WIDTH, side), (ORIG_HEIGHT, side), (PADDED_WIDTH, side), (PADDED_HEIGHT, side),
+            (BIG_SQUARE_SIZE, 1), (SMALL_SQUARE_SIZE, 1)]);
+        assert_eq!(tiny.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // as many range blocks as pixels, within the area
+        let side = 1 << 12;
+        let blocks = tampered(sample(), &[(ORIG_WIDTH, side), (ORIG_HEIGHT, side), (PADDED_WIDTH, side), (PADDED_HEIGHT, side),
+            (BIG_SQUARE_SIZE, 1), (SMALL_SQUARE_SIZE, 1)]);
+        assert_eq!(blocks.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn domains_bigger_than_the_image_are_out_of_range() {
+        let coder = DomainCoder::new(2, 2, 4, 4, 0, ABSOLUTE);
+        let small = RectCoords { x: 0, y: 0, width: 2, height: 2 };
+        assert_eq!(coder.decode(small, 0).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_rejects_odd_hv_big_squares() {
+        let picture = picture(16, 16, |x, y| (x * 7 + y * 13) as u8);
+        let comp = compress(&picture, CompSettings { big_square_size: 16, small_square_size: 4, partition: Partition::Hv, ..CompSettings::default() }).unwrap();
+        assert!(tampered(comp.clone(), &[]).is_ok());
+        let err = tampered(comp, &[(BIG_SQUARE_SIZE, 15)]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_rejects_hv_partitions_deeper_than_the_limit() {
+        let picture = noise(16, 16).to_rows();
+        let comp = compress(&picture, CompSettings { big_square_size: 16, small_square_size: 8, partition: Partition::Hv, split_depth: 1, ..CompSettings::default() }).unwrap();
+        let err = tampered(comp, &[(SPLIT_DEPTH, 1 << 20)]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "split depth is out of range");
+    }
+}
@@ -0,0 +1,264 @@
+use std::fmt;
+use std::ops;
+
+use byte_rect::*;
+use classify::{classify, BlockClass, CLASS_COUNT};
+use gray_image::{GrayImage, GrayView};
+use quant::CoeffQuantizer;
+use view::PixelSource;
+
+/// A domain block scaled down to range block size, in all eight isometries.
+#[derive(Debug, Clone)]
+pub struct Domain {
+    pub coords: SquareCoords,
+    pub roughness: u64,
+    /// Sums over the pixels, the same for every isometry.
+    pub sum: i64,
+    pub sqr_sum: i64,
+    pub class: BlockClass,
+    isometries: Vec<GrayImage>,
+}
+
+impl Domain {
+    pub fn new(coords: SquareCoords, scaled: GrayImage) -> Domain {
+        let view = scaled.as_view();
+        Domain {
+            coords,
+            roughness: scaled.roughness(),
+            sum: view.sum(),
+            sqr_sum: view.sqr_sum(),
+            class: classify(&view),
+            isometries: Transform::ALL.iter().map(|&t| scaled.transform(t)).collect(),
+        }
+    }
+
+    pub fn isometry(&self, t: Transform) -> &GrayImage {
+        &self.isometries[Transform::ALL.iter().position(|&u| u == t).unwrap()]
+    }
+
+    /// The sums to fit the `t` isometry of this domain onto `range`.
+    pub fn match_sums(&self, t: Transform, range: &RangeBlock) -> MatchSums {
+        MatchSums {
+            count: range.count,
+            self_sum: self.sum,
+            self_sqr_sum: self.sqr_sum,
+            other_sum: range.sum,
+            prod_sum: dot(&self.isometry(t).as_view(), &range.view),
+        }
+    }
+}
+
+/// Sum of the products of the pixels of two views of the same size.
+pub fn dot(a: &GrayView, b: &GrayView) -> i64 {
+    a.rows().zip(b.rows())
+        .map(|(a, b)| a.iter().zip(b.iter()).map(|(&p, &q)| p as i32 * q as i32).sum::<i32>() as i64)
+        .sum()
+}
+
+/// A range block together with the sums every candidate fit needs.
+#[derive(Debug, Clone, Copy)]
+pub struct RangeBlock<'a> {
+    pub coords: SquareCoords,
+    pub view: GrayView<'a>,
+    pub count: i64,
+    pub sum: i64,
+    pub sqr_sum: i64,
+    pub class: BlockClass,
+}
+
+impl<'a> RangeBlock<'a> {
+    pub fn new(image: &'a GrayImage, coords: SquareCoords) -> RangeBlock<'a> {
+        let view = image.view(coords.x, coords.y, coords.side, coords.side);
+        RangeBlock {
+            coords,
+            view,
+            count: (view.width() * view.height()) as i64,
+            sum: view.sum(),
+            sqr_sum: view.sqr_sum(),
+            class: classify(&view),
+        }
+    }
+}
+
+/// Every domain of an image, downscaled and transformed once up front and
+/// ordered from the smoothest to the roughest.
+#[derive(Debug, Clone)]
+pub struct DomainPool {
+    domains: Vec<Domain>,
+    /// Indices into `domains` for every `BlockClass::class`.
+    by_class: Vec<Vec<usize>>,
+    /// Distance between neighbouring domains.
+    step: usize,
+}
+
+impl DomainPool {
+    /// The pool of the tiles of `padded`.
+    pub fn new(padded: &GrayImage, big_square_size: usize, small_square_size: usize) -> DomainPool {
+        DomainPool::with_step(padded, big_square_size, small_square_size, 0)
+    }
+
+    /// The pool of the squares of `padded` on a lattice of the given step,
+    /// see `square_lattice`. Steps below the side make domains overlap.
+    pub fn with_step(padded: &GrayImage, big_square_size: usize, small_square_size: usize, step: usize) -> DomainPool {
+        let mut domains = square_lattice(padded.width(), padded.height(), big_square_size, step)
+            .into_iter()
+            .map(|cs| Domain::new(cs, padded.get_square(cs).scale_down(big_square_size / small_square_size)))
+            .collect::<Vec<_>>();
+        domains.sort_by_key(|d| d.roughness);
+        let mut by_class = vec![Vec::new(); CLASS_COUNT];
+        for (i, d) in domains.iter().enumerate() {
+            by_class[d.class.class].push(i);
+        }
+        DomainPool { domains, by_class, step: lattice_step(step, big_square_size) }
+    }
+
+    pub fn domains(&self) -> &[Domain] {
+        &self.domains
+    }
+
+    pub fn step(&self) -> usize {
+        self.step
+    }
+
+    /// Index into `domains()`, transform and quantized coefficients of the
+    /// closest match for `range`, stopping at the first one with a mean
+    /// squared error of at most `accept_mse`.
+    pub fn best_match(&self, range: &RangeBlock, quantizer: &CoeffQuantizer, accept_mse: f32) -> ((usize, Transform, LinearCoeffs), SearchStats) {
+        let candidates = (0..self.domains.len())
+            .flat_map(|i| Transform::ALL.iter().map(move |&t| (i, t)));
+        let (best, stats) = self.best_of(candidates, range, quantizer, accept_mse);
+        (best.expect("the domain pool is empty"), stats)
+    }
+
+    /// Indices into `domains()` of the domains in `class`.
+    pub fn in_class(&self, class: usize) -> &[usize] {
+        &self.by_class[class]
+    }
+
+    /// The best of the given domain and transform pairs, if there are any.
+    /// Candidates are tried in order and the rest are skipped once one is
+    /// within `accept_mse`.
+    pub fn best_of<I>(&self, candidates: I, range: &RangeBlock, quantizer: &CoeffQuantizer, accept_mse: f32) -> (Option<(usize, Transform, LinearCoeffs)>, SearchStats)
+        where I: Iterator<Item = (usize, Transform)> {
+        let accept = accept_mse as f64 * range.count as f64;
+        let mut candidates = candidates;
+        let mut stats = SearchStats::default();
+        let mut best: Option<(usize, Transform, LinearCoeffs, f64)> = None;
+        for (i, t) in candidates.by_ref() {
+            stats.compared += 1;
+            let sums = self.domains[i].match_sums(t, range);
+            let coeffs = quantizer.round(sums.coeffs(quantizer.max_factor));
+            let error = sums.sqr_error(coeffs, range.sqr_sum);
+            if best.is_none_or(|b| error < b.3) {
+                best = Some((i, t, coeffs, error));
+            }
+            if error <= accept {
+                break;
+            }
+        }
+        stats.skipped = candidates.count();
+        (best.map(|(i, t, coeffs, _)| (i, t, coeffs)), stats)
+    }
+
+    /// `best_of`, falling back to every domain when there are no candidates.
+    pub fn best_of_or_all<I>(&self, candidates: I, range: &RangeBlock, quantizer: &CoeffQuantizer, accept_mse: f32) -> ((usize, Transform, LinearCoeffs), SearchStats)
+        where I: Iterator<Item = (usize, Transform)> {
+        match self.best_of(candidates, range, quantizer, accept_mse) {
+            (Some(best), stats) => (best, stats),
+            (None, _) => self.best_match(range, quantizer, accept_mse),
+        }
+    }
+}
+
+/// How many domain and transform pairs a search fitted, and how many it left
+/// out because an earlier one was good enough.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct SearchStats {
+    pub compared: usize,
+    pub skipped: usize,
+}
+
+impl ops::AddAssign for SearchStats {
+    fn add_assign(&mut self, other: SearchStats) {
+        self.compared += other.compared;
+        self.skipped += other.skipped;
+    }
+}
+
+impl fmt::Display for SearchStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "compared {} candidates, skipped {} as good enough", self.compared, self.skipped)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use domain_pool::*;
+    use testing::{exact_quantizer, noise};
+
+    #[test]
+    fn domains_are_scaled_transformed_and_sorted_by_roughness() {
+        let img = noise(8, 8);
+        let pool = DomainPool::new(&img, 4, 2);
+        assert_eq!(pool.domains().len(), 4);
+        assert!(pool.domains().windows(2).all(|w| w[0].roughness <= w[1].roughness));
+        for d in pool.domains() {
+            let scaled = img.get_square(d.coords).scale_down(2);
+            for &t in Transform::ALL.iter() {
+                assert_eq!(d.isometry(t), &scaled.transform(t));
+            }
+            assert_eq!(d.sum, scaled.as_view().sum());
+        }
+    }
+
+    #[test]
+    fn cached_sums_match_the_direct_ones() {
+        let img = noise(8, 8);
+        let pool = DomainPool::new(&img, 4, 2);
+        let range = RangeBlock::new(&img, SquareCoords { x: 2, y: 4, side: 2 });
+        for d in pool.domains() {
+            for &t in Transform::ALL.iter() {
+                assert_eq!(d.match_sums(t, &range), d.isometry(t).as_view().match_sums(&range.view));
+            }
+        }
+    }
+
+    #[test]
+    fn sqr_error_is_close_to_the_real_distance() {
+        let img = noise(8, 8);
+        let pool = DomainPool::new(&img, 4, 2);
+        let range = RangeBlock::new(&img, SquareCoords { x: 0, y: 2, side: 2 });
+        let d = &pool.domains()[1];
+        let coeffs = LinearCoeffs { shift: 20, factor: 0.5 };
+        let sums = d.match_sums(Transform::HeadToLeft, &range);
+        let exact = d.isometry(Transform::HeadToLeft).as_view().linear_dist(coeffs, &range.view);
+        // linear truncates, which is off by less than one per pixel
+        assert!((sums.sqr_error(coeffs, range.sqr_sum) - exact as f64).abs() < 4.0 * (2.0 * 255.0 + 1.0));
+    }
+
+    #[test]
+    fn best_match_finds_an_exact_copy() {
+        let img = noise(8, 8);
+        let pool = DomainPool::new(&img, 4, 2);
+        let target = pool.domains()[2].isometry(Transform::HeadToBottomInv).clone();
+        let range = RangeBlock::new(&target, SquareCoords { x: 0, y: 0, side: 2 });
+        let ((i, t, coeffs), _) = pool.best_match(&range, &exact_quantizer(), 0.0);
+        assert_eq!(pool.domains()[i].isometry(t), &target);
+        assert_eq!(coeffs.factor, 1.0);
+    }
+
+    #[test]
+    fn search_stops_at_an_acceptable_match() {
+        let img = noise(8, 8);
+        let pool = DomainPool::new(&img, 4, 2);
+        let range = RangeBlock::new(&img, SquareCoords { x: 0, y: 2, side: 2 });
+        let quantizer = CoeffQuantizer::default();
+        let (_, all) = pool.best_match(&range, &quantizer, 0.0);
+        assert_eq!(all.compared + all.skipped, pool.domains().len() * Transform::ALL.len());
+        // anything goes, so the first candidate is taken
+        let (first, stats) = pool.best_match(&range, &quantizer, f32::INFINITY);
+        assert_eq!((first.0, first.1), (0, Transform::ALL[0]));
+        assert_eq!(stats, SearchStats { compared: 1, skipped: all.compared + all.skipped - 1 });
+    }
+}
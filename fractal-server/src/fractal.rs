@@ -1,53 +1,217 @@
-use byte_rect::*;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 
-static TRANSFORMS: &'static [Transform] = &[
-    Transform::HeadToTop, Transform::HeadToRight, Transform::HeadToBottom, Transform::HeadToLeft,
-    Transform::HeadToTopInv, Transform::HeadToRightInv, Transform::HeadToBottomInv, Transform::HeadToLeftInv,
-];
+use byte_rect::*;
+use domain_pool::{DomainPool, RangeBlock, SearchStats};
+use hv::HvDomains;
+use partition::{Partition, Split, MAX_SPLIT_DEPTH};
+use rd::{self, Candidate, RateDistortion, Target};
+use search::{Search, SearchStrategy};
+use gray_image::GrayImage;
+use view::{PixelSource, Transformed};
+use quant::CoeffQuantizer;
+use container;
 
-pub fn find_closest_square<'a, 'b, R>(squares: &'a [R], desired: &'b R) -> (usize, Transform, LinearCoeffs) where R: ByteRect {
+/// Picks the square, transform and coefficients that reproduce `desired` best
+/// once the coefficients have gone through `quantizer`, as the decoder will
+/// only ever see the quantized ones. Candidates are only ever read through
+/// `Transformed` views, nothing is copied.
+#[allow(dead_code)]
+pub fn find_closest_square<S, D>(squares: &[S], desired: &D, quantizer: &CoeffQuantizer) -> (usize, Transform, LinearCoeffs) where S: PixelSource, D: PixelSource {
     let (best_i, best_transform, best_coeffs, _) = squares.iter()
         .enumerate()
-        .flat_map(|(i, sq)| TRANSFORMS.iter().map(move |&t| (i, t, sq.transform(t))))
-        .map(|(i, t, sq)| (i, t, sq.best_coeffs_to_match(desired), sq))
-        .min_by_key(|&(_, _, _, ref sq)| desired.dist(&sq.linear(sq.best_coeffs_to_match(desired))))
+        .flat_map(|(i, sq)| Transform::ALL.iter().map(move |&t| (i, t, Transformed::new(sq, t))))
+        .map(|(i, t, sq)| {
+            let coeffs = quantizer.round(sq.match_sums(desired).coeffs(quantizer.max_factor));
+            (i, t, coeffs, sq.linear_dist(coeffs, desired))
+        })
+        .min_by_key(|&(_, _, _, dist)| dist)
         .unwrap();
     (best_i, best_transform, best_coeffs)
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct SquareMapping {
-    pub small: SquareCoords,
-    pub big: SquareCoords,
+    pub small: RectCoords,
+    pub big: RectCoords,
     pub trans: Transform,
     pub coeffs: LinearCoeffs,
+    /// How many bits fewer than the settings say both coefficients are
+    /// stored with, see `CompSettings::precision_levels`.
+    pub coarseness: u8,
+}
+
+/// The domains range blocks that have been split a given number of times are
+/// matched against, and how they are searched.
+struct Level {
+    pool: DomainPool,
+    strategy: Box<dyn SearchStrategy>,
+}
+
+/// Where the encoder looks for domains, depending on `CompSettings::partition`.
+enum Domains {
+    /// One level per quadtree depth, from the range grid squares down.
+    Quadtree(Vec<Level>),
+    Hv(HvDomains),
+}
+
+/// A domain, transform and coefficients for a range block, and the sums they
+/// were fitted from.
+type Found = (RectCoords, Transform, LinearCoeffs, MatchSums);
+
+impl Domains {
+    fn best_match(&self, padded: &GrayImage, node: RectCoords, depth: usize, settings: &CompSettings) -> (Found, SearchStats) {
+        let quantizer = &settings.quantizer();
+        match *self {
+            Domains::Quadtree(ref levels) => {
+                let level = &levels[settings.split_depth - depth];
+                let range = RangeBlock::new(padded, SquareCoords { x: node.x, y: node.y, side: node.width });
+                let ((best_i, trans, coeffs), stats) = level.strategy.best_match(&level.pool, &range, quantizer, settings.accept_mse);
+                let domain = &level.pool.domains()[best_i];
+                ((domain.coords.into(), trans, coeffs, domain.match_sums(trans, &range)), stats)
+            }
+            Domains::Hv(ref domains) => domains.best_match(padded, node, quantizer, settings.accept_mse),
+        }
+    }
+}
+
+/// What the encoder found for one square of the range grid.
+#[derive(Default)]
+struct Encoded {
+    partition: Vec<Split>,
+    mapping: Vec<SquareMapping>,
+    stats: SearchStats,
+    /// What rate-distortion optimisation expects of the mapping.
+    estimate: Option<RateDistortion>,
+}
+
+/// Maps `node` onto its best domain, or, if that one is worse than
+/// `split_mse` and `depth` more splits are allowed, each of its parts.
+fn encode_block(padded: &GrayImage, domains: &Domains, settings: &CompSettings, node: RectCoords, depth: usize, out: &mut Encoded) {
+    let ((big, trans, coeffs, sums), stats) = domains.best_match(padded, node, depth, settings);
+    out.stats += stats;
+    let error = sums.sqr_error(coeffs, padded.view(node.x, node.y, node.width, node.height).sqr_sum());
+    if error > settings.split_mse as f64 * (node.width * node.height) as f64 {
+        if let Some(split) = settings.partition.split(padded, node, depth) {
+            out.partition.push(split);
+            for part in split.parts(node) {
+                encode_block(padded, domains, settings, part, depth - 1, out);
+            }
+            return;
+        }
+    }
+    out.partition.push(Split::Leaf);
+    out.mapping.push(SquareMapping { small: node, big, trans, coeffs, coarseness: 0 });
 }
 
-fn get_closest_chunk_mapping<R>(padded: &R, big_grid_size: usize, small_grid_size: usize, group_count: usize) -> Vec<SquareMapping> where R: ByteRect {
-    let small_grid = padded.to_square_chunks(small_grid_size);
-    let big_grid = padded.to_square_chunks(big_grid_size);
-    let mut big_grid_with_roughness = big_grid
-        .iter()
-        .map(|&(cs, ref big_chunk)| (cs, big_chunk.scale_down(big_grid_size / small_grid_size)))
-        .map(|(cs, chunk)| (cs, chunk.roughness(), chunk))
-        .collect::<Vec<(SquareCoords, u64, R)>>();
-    big_grid_with_roughness.sort_by_key(|&(_, r, _)| r);
-    let (big_coords, big_squares): (Vec<_>, Vec<R>) = big_grid_with_roughness
-        .into_iter()
-        .map(|(cs, _, chunk)| (cs, chunk))
-        .unzip();
-    let mut i = 0;
-    small_grid
-        .iter()
-        .map(|&(small_cs, ref small_chunk)| {
-            i += 1;
-            if i % 100 == 0 {
+/// Every way of coding `node` and its parts, see `rd`.
+fn candidate(padded: &GrayImage, domains: &Domains, settings: &CompSettings, node: RectCoords, depth: usize, stats: &mut SearchStats) -> Candidate {
+    let ((big, trans, _, sums), found_stats) = domains.best_match(padded, node, depth, settings);
+    *stats += found_stats;
+    let sqr_sum = padded.view(node.x, node.y, node.width, node.height).sqr_sum();
+    let leaves = (0..=settings.precision_levels)
+        .map(|coarseness| {
+            let coeffs = settings.coarse_quantizer(coarseness).round(sums.coeffs(settings.max_factor));
+            let map = SquareMapping { small: node, big, trans, coeffs, coarseness };
+            (map, container::block_bits(settings, padded.width(), padded.height(), &map), sums.sqr_error(coeffs, sqr_sum))
+        })
+        .collect();
+    let leaf_bits = if settings.partition.can_split(node, depth) { container::split_bits(node, Split::Leaf) } else { 0 };
+    let split = settings.partition.split(padded, node, depth).map(|split| {
+        let parts = split.parts(node)
+            .into_iter()
+            .map(|part| candidate(padded, domains, settings, part, depth - 1, stats))
+            .collect();
+        (split, container::split_bits(node, split), parts)
+    });
+    Candidate { leaves, leaf_bits, split }
+}
+
+/// `code_root` of every square of the range grid, in grid order.
+fn for_each_root<T, F>(small_grid: &[SquareCoords], threads: usize, code_root: F) -> Vec<T>
+    where T: Send, F: Fn(RectCoords) -> T + Sync {
+    let done = AtomicUsize::new(0);
+    let search = || small_grid
+        .par_iter()
+        .map(|&small_cs| {
+            let i = done.fetch_add(1, Ordering::Relaxed) + 1;
+            if i.is_multiple_of(100) {
                 println!("processing {} out of {}", i, small_grid.len());
             }
-            let (best_i, best_trans, best_coeffs) = find_closest_square(&big_squares[0..big_squares.len() / group_count], &small_chunk);
-            SquareMapping { small: small_cs, big: big_coords[best_i], trans: best_trans, coeffs: best_coeffs }
+            code_root(small_cs.into())
         })
-        .collect()
+        .collect::<Vec<_>>();
+    // the blocks are searched independently and collected in grid order,
+    // so the mapping doesn't depend on the thread count
+    if threads == 0 {
+        search()
+    } else {
+        search_pool(threads).install(search)
+    }
+}
+
+/// A pool of `threads` search threads, started on first use and shared by
+/// every later search asking for as many.
+fn search_pool(threads: usize) -> Arc<ThreadPool> {
+    static POOLS: OnceLock<Mutex<HashMap<usize, Arc<ThreadPool>>>> = OnceLock::new();
+    let mut pools = POOLS.get_or_init(Default::default).lock().unwrap_or_else(|e| e.into_inner());
+    pools.entry(threads)
+        .or_insert_with(|| Arc::new(ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .expect("failed to start the search threads")))
+        .clone()
+}
+
+fn get_closest_chunk_mapping(padded: &GrayImage, settings: &CompSettings, pixels: usize) -> Encoded {
+    let CompSettings { big_square_size, small_square_size, grouping_factor, search, threads, split_depth, partition, domain_step, .. } = *settings;
+    let small_grid = square_grid(padded.width(), padded.height(), small_square_size);
+    let domains = match partition {
+        Partition::Quadtree => Domains::Quadtree((0..=split_depth)
+            .map(|depth| {
+                let pool = DomainPool::with_step(padded, big_square_size >> depth, small_square_size >> depth, domain_step);
+                let strategy = search.strategy(&pool, grouping_factor);
+                Level { pool, strategy }
+            })
+            .collect()),
+        Partition::Hv => Domains::Hv(HvDomains::new(padded, domain_step)),
+    };
+    let mut total = Encoded::default();
+    match settings.target {
+        None => {
+            let found = for_each_root(&small_grid, threads, |root| {
+                let mut encoded = Encoded::default();
+                encode_block(padded, &domains, settings, root, split_depth, &mut encoded);
+                encoded
+            });
+            for encoded in found {
+                total.partition.extend(encoded.partition);
+                total.mapping.extend(encoded.mapping);
+                total.stats += encoded.stats;
+            }
+        }
+        Some(target) => {
+            let found = for_each_root(&small_grid, threads, |root| {
+                let mut stats = SearchStats::default();
+                (candidate(padded, &domains, settings, root, split_depth, &mut stats), stats)
+            });
+            let mut candidates = Vec::with_capacity(found.len());
+            for (candidate, stats) in found {
+                candidates.push(candidate);
+                total.stats += stats;
+            }
+            let (partition, mapping, estimate) = rd::optimize(&candidates, target, pixels, container::overhead_bits(settings));
+            total.partition = partition;
+            total.mapping = mapping;
+            total.estimate = Some(estimate);
+        }
+    }
+    total
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -56,58 +220,350 @@ pub struct Compressed {
     pub orig_height: usize,
     pub padded_width: usize,
     pub padded_height: usize,
+    /// What the container stores of the settings, see `CompSettings::stored`.
+    pub settings: CompSettings,
+    /// How every node of the partition of the range grid squares is split,
+    /// see `partition`.
+    pub partition: Vec<Split>,
+    /// One per range block, in the order `partition::leaves` gives them.
     pub mapping: Vec<SquareMapping>,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct CompSettings {
     pub big_square_size: usize,
     pub small_square_size: usize,
+    /// `Search::Smoothest` only tries the smoothest `1/grouping_factor` of the
+    /// domains. Only the encoder needs it, so it isn't stored.
     pub grouping_factor: usize,
+    /// Bit depth the contrast (`LinearCoeffs::factor`) is stored with.
+    pub factor_bits: u8,
+    /// Bit depth the brightness (`LinearCoeffs::shift`) is stored with.
+    pub shift_bits: u8,
+    /// Upper bound for `|factor|`. Keeping it at or below 1 makes every mapping
+    /// contractive, so decompression converges instead of oscillating.
+    pub max_factor: f32,
+    /// How domains are looked for; only the encoder needs to know, so it
+    /// isn't stored.
+    pub search: Search,
+    /// Threads searching for range block matches, 0 means one per core.
+    /// The output is the same for any value, so it isn't stored.
+    pub threads: usize,
+    /// A range block's search stops at the first candidate with at most
+    /// this mean squared error per pixel. 0 only stops at exact matches.
+    pub accept_mse: f32,
+    /// How range blocks are split. HV blocks always search every domain of
+    /// their shape, whatever `search` says.
+    pub partition: Partition,
+    /// How many times range blocks may be split. Quadtree blocks end up with
+    /// a side of at least `small_square_size >> split_depth`.
+    pub split_depth: usize,
+    /// Range blocks whose best match has a larger mean squared error per
+    /// pixel are split while `split_depth` allows. Only the encoder needs it.
+    pub split_mse: f32,
+    /// Distance in pixels between neighbouring domains, see `lattice_step`.
+    /// Steps below the domain side give many more, overlapping candidates.
+    /// 0 tiles the image with them. HV needs an even step.
+    pub domain_step: usize,
+    /// Makes the encoder pick the partition and coefficient precision of
+    /// every block to meet this instead of following `split_mse`, see `rd`.
+    /// Only the encoder needs it.
+    pub target: Option<Target>,
+    /// How many coarser coefficient precisions, each one bit shallower for
+    /// both coefficients, range blocks may be stored with. Only `target`
+    /// encoding uses them, but every block pays for telling which it has.
+    pub precision_levels: u8,
+}
+
+impl Default for CompSettings {
+    fn default() -> CompSettings {
+        CompSettings {
+            big_square_size: 16,
+            small_square_size: 4,
+            grouping_factor: 20,
+            factor_bits: 5,
+            shift_bits: 7,
+            max_factor: 1.0,
+            search: Search::Smoothest,
+            threads: 0,
+            accept_mse: 0.0,
+            partition: Partition::Quadtree,
+            split_depth: 0,
+            split_mse: 100.0,
+            domain_step: 0,
+            target: None,
+            precision_levels: 0,
+        }
+    }
 }
 
+impl CompSettings {
+    pub fn quantizer(&self) -> CoeffQuantizer {
+        CoeffQuantizer { factor_bits: self.factor_bits, shift_bits: self.shift_bits, max_factor: self.max_factor }
+    }
+
+    /// The quantizer of range blocks with the given `SquareMapping::coarseness`.
+    pub fn coarse_quantizer(&self, coarseness: u8) -> CoeffQuantizer {
+        CoeffQuantizer { factor_bits: self.factor_bits - coarseness, shift_bits: self.shift_bits - coarseness, ..self.quantizer() }
+    }
+
+    /// These settings with the ones only the encoder needs back at their
+    /// defaults, which is all a decoder can know of them.
+    pub fn stored(&self) -> CompSettings {
+        let encoder = CompSettings::default();
+        CompSettings {
+            grouping_factor: encoder.grouping_factor,
+            search: encoder.search,
+            threads: encoder.threads,
+            accept_mse: encoder.accept_mse,
+            split_mse: encoder.split_mse,
+            target: encoder.target,
+            ..*self
+        }
+    }
+
+    /// Fails with what is wrong unless `compress` can work with these settings.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.small_square_size == 0 || self.big_square_size < self.small_square_size {
+            return Err("big square size must be at least the small square size, which must be positive".to_string());
+        }
+        if !self.big_square_size.is_multiple_of(self.small_square_size) {
+            return Err("big square size must be a multiple of small square size".to_string());
+        }
+        if self.grouping_factor == 0 {
+            return Err("grouping factor must be positive".to_string());
+        }
+        if self.factor_bits == 0 || self.factor_bits > 24 || self.shift_bits == 0 || self.shift_bits > 24 {
+            return Err("coefficient bit depths must be within 1..24".to_string());
+        }
+        if !(self.max_factor > 0.0 && self.max_factor.is_finite()) {
+            return Err("max factor must be positive".to_string());
+        }
+        if self.accept_mse.is_nan() || self.accept_mse < 0.0 {
+            return Err("acceptable error must not be negative".to_string());
+        }
+        if let Some(Target::Bpp(target) | Target::Psnr(target)) = self.target {
+            if !(target > 0.0 && target.is_finite()) {
+                return Err("target must be positive".to_string());
+            }
+        }
+        if self.precision_levels > 0 && self.precision_levels >= self.factor_bits.min(self.shift_bits) {
+            return Err("precision levels must leave both coefficients at least one bit".to_string());
+        }
+        if self.split_depth > MAX_SPLIT_DEPTH {
+            return Err(format!("split depth must be at most {}", MAX_SPLIT_DEPTH));
+        }
+        match self.partition {
+            Partition::Quadtree => if !self.small_square_size.is_multiple_of(1 << self.split_depth) {
+                return Err("small square size must stay whole after every split".to_string());
+            },
+            Partition::Hv => {
+                if self.big_square_size < 2 * self.small_square_size {
+                    return Err("hv partitioning needs big squares at least twice the small ones".to_string());
+                }
+                if !self.big_square_size.is_multiple_of(2) {
+                    return Err("hv partitioning needs an even big square size".to_string());
+                }
+                if !self.domain_step.is_multiple_of(2) {
+                    return Err("hv partitioning needs an even domain step".to_string());
+                }
+            }
+        }
+        if self.split_mse.is_nan() || self.split_mse < 0.0 {
+            return Err("split error must not be negative".to_string());
+        }
+        if self.search == (Search::Nearest { k: 0 }) {
+            return Err("nearest search needs at least one neighbour".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// How big `decompress` paints the image. Fractal codes don't have a
+/// resolution of their own, so any size works, and zooming in makes up
+/// detail rather than blowing up pixels.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OutputSize {
+    /// The original size times this factor on both axes.
+    Scaled(f32),
+    /// Exactly this size, stretching either axis as needed.
+    Exact { width: usize, height: usize },
+}
+
+impl OutputSize {
+    /// The output size for an original of `width x height`.
+    pub fn of(self, width: usize, height: usize) -> (usize, usize) {
+        match self {
+            OutputSize::Scaled(factor) => {
+                let scale = |side: usize| ((side as f32 * factor).round() as usize).max(1);
+                (scale(width), scale(height))
+            }
+            OutputSize::Exact { width, height } => (width, height),
+        }
+    }
+}
+
+/// How a decoding pass updates the image.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Update {
+    /// Every range block is painted from the previous pass.
+    Jacobi,
+    /// Range blocks are painted in place, so later ones already see what
+    /// earlier ones painted in the same pass. Converges in fewer passes.
+    GaussSeidel,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct DecompSettings {
+    /// Most passes decoding takes.
     pub iterations: usize,
+    /// Decoding stops early after a pass changing the image by at most this
+    /// mean squared error per pixel. 0 only stops once nothing changes,
+    /// which gives the same image as going on.
+    pub converge_mse: f32,
+    pub size: OutputSize,
+    pub update: Update,
+    /// Decoding first runs at a half, quarter... of the output size for this
+    /// many levels, each starting from the last one blown up, so only the
+    /// smallest starts from flat grey.
+    pub pyramid_levels: usize,
+    /// Most passes every level above the smallest takes, which `iterations`
+    /// bounds instead.
+    pub refine_passes: usize,
+}
+
+impl Default for DecompSettings {
+    fn default() -> DecompSettings {
+        DecompSettings { iterations: 20, converge_mse: 0.0, size: OutputSize::Scaled(1.0), update: Update::Jacobi, pyramid_levels: 0, refine_passes: 2 }
+    }
+}
+
+/// Fails if the settings don't `validate` or the image is empty.
+pub fn compress(image: &[Vec<u8>], settings: CompSettings) -> io::Result<Compressed> {
+    compress_with_stats(image, settings).map(|(comp, _, _)| comp)
 }
 
-pub fn compress(image: &Vec<Vec<u8>>, settings: CompSettings) -> Compressed {
-    let padded = image.pad_to_divisible_by(settings.big_square_size);
-    let mapping = get_closest_chunk_mapping(&padded, settings.big_square_size, settings.small_square_size, settings.grouping_factor);
-    Compressed {
+/// `compress`, also telling how much of the search `accept_mse` saved and,
+/// with a `target`, the rate and distortion expected of the result.
+pub fn compress_with_stats(image: &[Vec<u8>], settings: CompSettings) -> io::Result<(Compressed, SearchStats, Option<RateDistortion>)> {
+    settings.validate().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if image.is_empty() || image[0].is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty image"));
+    }
+    let padded = GrayImage::from_rows(image).pad_to_divisible_by(settings.big_square_size);
+    let Encoded { partition, mapping, stats, estimate } = get_closest_chunk_mapping(&padded, &settings, image.len() * image[0].len());
+    let comp = Compressed {
         orig_width: image[0].len(),
         orig_height: image.len(),
-        padded_width: padded[0].len(),
-        padded_height: padded.len(),
-        mapping: mapping,
+        padded_width: padded.width(),
+        padded_height: padded.height(),
+        settings: settings.stored(),
+        partition,
+        mapping,
+    };
+    Ok((comp, stats, estimate))
+}
+
+/// Maps the original image onto a decoded one of `out` pixels.
+struct Zoom {
+    orig: (usize, usize),
+    out: (usize, usize),
+}
+
+impl Zoom {
+    fn floor(&self, x: usize, y: usize) -> (usize, usize) {
+        (x * self.out.0 / self.orig.0, y * self.out.1 / self.orig.1)
+    }
+
+    fn ceil(&self, x: usize, y: usize) -> (usize, usize) {
+        ((x * self.out.0).div_ceil(self.orig.0), (y * self.out.1).div_ceil(self.orig.1))
+    }
+
+    /// Range blocks keep tiling the image, though some may shrink to nothing.
+    fn range(&self, r: RectCoords) -> RectCoords {
+        let ((x, y), (end_x, end_y)) = (self.floor(r.x, r.y), self.floor(r.x + r.width, r.y + r.height));
+        RectCoords { x, y, width: end_x - x, height: end_y - y }
+    }
+
+    /// Domains take every pixel they touch, so they keep at least one.
+    fn domain(&self, r: RectCoords) -> RectCoords {
+        let ((x, y), (end_x, end_y)) = (self.floor(r.x, r.y), self.ceil(r.x + r.width, r.y + r.height));
+        RectCoords { x, y, width: end_x - x, height: end_y - y }
     }
 }
 
 pub fn decompress(comp: &Compressed, settings: DecompSettings) -> Vec<Vec<u8>> {
-    let image: Vec<Vec<u8>> = (0..comp.padded_height)
-        .map(|_| (0..comp.padded_width).map(|_| 128).collect())
-        .collect();
-    let iterated = apply_square_mapping_rec(&image, &comp.mapping, settings.iterations);
-    iterated.get_rect(0, 0, comp.orig_width, comp.orig_height)
-}
-
-fn apply_square_mapping_rec(image: &Vec<Vec<u8>>, mapping: &Vec<SquareMapping>, depth: usize) -> Vec<Vec<u8>> {
-    let mut result: Vec<Vec<u8>> = image.clone();
-    for &map in mapping.iter() {
-        let SquareMapping { small, big, trans, coeffs } = map;
-        assert!(big.side % small.side == 0, "can't scale when big side is not divisible by small side");
-        let new_square = image
-            .get_square(big)
-            .scale_down(big.side / small.side)
-            .transform(trans)
-            .linear(coeffs);
-        for x in 0..small.side {
-            for y in 0..small.side {
-                result[small.y + y][small.x + x] = new_square[y][x];
+    decompress_with_stats(comp, settings).0
+}
+
+/// `decompress`, also telling how many passes it took on all levels.
+pub fn decompress_with_stats(comp: &Compressed, settings: DecompSettings) -> (Vec<Vec<u8>>, usize) {
+    let (width, height) = settings.size.of(comp.orig_width, comp.orig_height);
+    assert!(width > 0 && height > 0, "can't decode to an empty image");
+    let mut image: Option<GrayImage> = None;
+    let mut passes = 0;
+    for level in (0..settings.pyramid_levels + 1).rev() {
+        let zoom = Zoom { orig: (comp.orig_width, comp.orig_height), out: ((width >> level).max(1), (height >> level).max(1)) };
+        let mapping = comp.mapping.iter()
+            .map(|map| SquareMapping { small: zoom.range(map.small), big: zoom.domain(map.big), ..*map })
+            .filter(|map| map.small.width > 0 && map.small.height > 0)
+            .collect::<Vec<_>>();
+        let (padded_width, padded_height) = zoom.ceil(comp.padded_width, comp.padded_height);
+        let (mut level_image, most_passes) = match image {
+            Some(smaller) => (smaller.as_view().resize(padded_width, padded_height), settings.refine_passes),
+            None => (GrayImage::from_fn(padded_width, padded_height, |_, _| 128), settings.iterations),
+        };
+        passes += iterate(&mut level_image, &mapping, most_passes, settings);
+        image = Some(level_image);
+    }
+    (image.expect("no levels decoded").get_rect(0, 0, width, height).to_rows(), passes)
+}
+
+/// Runs decoding passes of `mapping` over `image` until `settings` says it
+/// has converged or `most_passes` ran, telling how many did.
+fn iterate(image: &mut GrayImage, mapping: &[SquareMapping], most_passes: usize, settings: DecompSettings) -> usize {
+    let pixels = (image.width() * image.height()) as f64;
+    let mut passes = 0;
+    while passes < most_passes {
+        let mut change = 0;
+        match settings.update {
+            Update::Jacobi => {
+                let previous = image.clone();
+                for map in mapping.iter() {
+                    change += repaint(image, map.small, &paint(&previous, map));
+                }
             }
+            Update::GaussSeidel => for map in mapping.iter() {
+                let block = paint(image, map);
+                change += repaint(image, map.small, &block);
+            },
+        }
+        passes += 1;
+        if change as f64 / pixels <= settings.converge_mse as f64 {
+            break;
         }
     }
-    if depth == 1 { result } else { apply_square_mapping_rec(&result, mapping, depth - 1) }
+    passes
+}
+
+/// What `map` paints onto its range block of `image`.
+fn paint(image: &GrayImage, map: &SquareMapping) -> GrayImage {
+    let SquareMapping { small, big, trans, coeffs, .. } = *map;
+    // shrunk to what the transform turns into the shape of the range block
+    let (width, height) = if trans.transposes() { (small.height, small.width) } else { (small.width, small.height) };
+    image
+        .view(big.x, big.y, big.width, big.height)
+        .resize(width, height)
+        .transform(trans)
+        .linear(coeffs)
+}
+
+/// Pastes `block` over `small`, telling by how much squared error that
+/// changed the image.
+fn repaint(image: &mut GrayImage, small: RectCoords, block: &GrayImage) -> u64 {
+    let change = image.view(small.x, small.y, small.width, small.height).dist(&block.as_view());
+    image.paste(small.x, small.y, block);
+    change
 }
 
 
@@ -115,7 +571,8 @@ fn apply_square_mapping_rec(image: &Vec<Vec<u8>>, mapping: &Vec<SquareMapping>,
 #[cfg(test)]
 mod tests {
     use fractal::*;
-    use byte_rect::*;
+    use byte_rect::ByteRect;
+    use testing::{decoded_dist, noise, noise_at, picture};
 
     #[test]
     fn clone_on_2d_vec_is_deep() {
@@ -126,6 +583,12 @@ mod tests {
         assert_eq!(v2, vec![vec![1,-2,3]]);
     }
 
+    fn find_closest_in_rows(options: &[Vec<Vec<u8>>], desired: &[Vec<u8>], quantizer: &CoeffQuantizer) -> (usize, Transform, LinearCoeffs) {
+        let images = options.iter().map(|o| GrayImage::from_rows(o)).collect::<Vec<_>>();
+        let views = images.iter().map(|img| img.as_view()).collect::<Vec<_>>();
+        find_closest_square(&views, &GrayImage::from_rows(desired).as_view(), quantizer)
+    }
+
     #[test]
     fn find_closest_rect_chooses_the_perfect_matching_out_of_three() {
         let desired = vec![
@@ -146,12 +609,36 @@ mod tests {
                 vec![5, 5]
             ],
         ];
-        let (best_match_index, best_trans, best_coeffs) = find_closest_square(&options, &desired);
+        let (best_match_index, best_trans, best_coeffs) = find_closest_in_rows(&options, &desired, &CoeffQuantizer::default());
         assert_eq!(best_match_index, 1);
         assert_eq!(best_trans, Transform::HeadToRightInv);
         assert_eq!(best_coeffs, LinearCoeffs { shift: 4, factor: -0.5 } )
     }
 
+    #[test]
+    fn find_closest_rect_accounts_for_quantization_error() {
+        let desired = vec![
+            vec![0, 0],
+            vec![0, 100]
+        ];
+        let options = vec![
+            vec![
+                vec![0, 0],
+                vec![0, 4]
+            ],
+            vec![
+                vec![0, 0],
+                vec![0, 160]
+            ],
+        ];
+        // the first option is perfect, but only with a factor the quantizer can't store
+        assert_eq!(desired.dist(&options[0].linear(options[0].best_coeffs_to_match(&desired))), 0);
+        let quantizer = CoeffQuantizer { factor_bits: 4, ..CoeffQuantizer::default() };
+        let (best_match_index, _, best_coeffs) = find_closest_in_rows(&options, &desired, &quantizer);
+        assert_eq!(best_match_index, 1);
+        assert_eq!(quantizer.round(best_coeffs), best_coeffs);
+    }
+
     #[test]
     fn closest_chunks_chooses_as_many_matches_as_there_are_small_squares() {
         let picture = vec![
@@ -167,11 +654,40 @@ mod tests {
             big_square_size: 4,
             small_square_size: 2,
             grouping_factor: 1,
-        });
+            ..CompSettings::default()
+        }).unwrap();
         let small_square_count = 4 * 4;
         assert_eq!(mapping.len(), small_square_count);
     }
 
+    #[test]
+    fn compress_keeps_factors_within_the_limit() {
+        let picture = picture(16, 16, |x, y| if (x + y) % 2 == 0 { 0 } else { 255 });
+        let Compressed{mapping, ..} = compress(&picture, CompSettings {
+            big_square_size: 8,
+            small_square_size: 4,
+            grouping_factor: 1,
+            max_factor: 0.75,
+            ..CompSettings::default()
+        }).unwrap();
+        assert!(mapping.iter().all(|m| m.coeffs.factor.abs() <= 0.75));
+    }
+
+    #[test]
+    fn compress_rejects_settings_it_cannot_work_with() {
+        let picture = noise(16, 16).to_rows();
+        for &settings in [
+            CompSettings { big_square_size: 0, ..CompSettings::default() },
+            CompSettings { big_square_size: 4, small_square_size: 8, ..CompSettings::default() },
+            CompSettings { split_depth: 3, ..CompSettings::default() },
+            CompSettings { search: Search::Nearest { k: 0 }, ..CompSettings::default() },
+        ].iter() {
+            assert!(settings.validate().is_err());
+            assert_eq!(compress(&picture, settings).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(compress(&[], CompSettings::default()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
     #[test]
     fn restores_image_to_original_size() {
         let picture = vec![
@@ -187,8 +703,9 @@ mod tests {
             big_square_size: 4,
             small_square_size: 2,
             grouping_factor: 1,
-        });
-        let restored = decompress(&compressed, DecompSettings { iterations: 10 });
+            ..CompSettings::default()
+        }).unwrap();
+        let restored = decompress(&compressed, DecompSettings { iterations: 10, ..DecompSettings::default() });
         assert_eq!(restored.len(), 7);
         assert_eq!(restored[0].len(), 5);
     }
@@ -212,9 +729,153 @@ mod tests {
             big_square_size: 4,
             small_square_size: 2,
             grouping_factor: 1,
-        });
-        let restored = decompress(&compressed, DecompSettings { iterations: 10 });
+            shift_bits: 10,
+            ..CompSettings::default()
+        }).unwrap();
+        let restored = decompress(&compressed, DecompSettings { iterations: 10, ..DecompSettings::default() });
         let dist = picture.dist(&restored);
         assert!(dist == 0, "dist was not 0: \n{}", print_image(restored));
     }
+
+    #[test]
+    fn thread_count_does_not_change_the_output() {
+        let picture = noise(24, 24).to_rows();
+        let settings = CompSettings { big_square_size: 8, small_square_size: 4, grouping_factor: 1, ..CompSettings::default() };
+        let sequential = compress(&picture, CompSettings { threads: 1, ..settings }).unwrap();
+        assert_eq!(compress(&picture, CompSettings { threads: 4, ..settings }).unwrap().mapping, sequential.mapping);
+        assert_eq!(compress(&picture, settings).unwrap().mapping, sequential.mapping);
+    }
+
+    #[test]
+    fn searches_share_a_pool_per_thread_count() {
+        assert!(Arc::ptr_eq(&search_pool(3), &search_pool(3)));
+        assert!(!Arc::ptr_eq(&search_pool(3), &search_pool(2)));
+        assert_eq!(search_pool(3).current_num_threads(), 3);
+    }
+
+    #[test]
+    fn nearest_neighbour_search_with_every_candidate_matches_the_full_search() {
+        let picture = noise(16, 16).to_rows();
+        let settings = CompSettings { big_square_size: 8, small_square_size: 4, grouping_factor: 1, ..CompSettings::default() };
+        let all_points = 4 * 8 * 2;
+        let nearest = compress(&picture, CompSettings { search: Search::Nearest { k: all_points }, ..settings }).unwrap();
+        assert_eq!(nearest.mapping, compress(&picture, CompSettings { search: Search::Exhaustive, ..settings }).unwrap().mapping);
+    }
+
+    #[test]
+    fn acceptable_error_saves_comparisons() {
+        let picture = picture(16, 16, |x, y| (x * 7 + y * 3) as u8);
+        let settings = CompSettings { big_square_size: 8, small_square_size: 4, search: Search::Exhaustive, ..CompSettings::default() };
+        let (_, full, _) = compress_with_stats(&picture, settings).unwrap();
+        let (_, early, _) = compress_with_stats(&picture, CompSettings { accept_mse: 10.0, ..settings }).unwrap();
+        assert_eq!(early.compared + early.skipped, full.compared + full.skipped);
+        assert_eq!(full.compared + full.skipped, 16 * 4 * 8);
+        assert!(early.skipped > full.skipped);
+    }
+
+    #[test]
+    fn quadtree_only_splits_detailed_blocks() {
+        // flat on the left, noise on the right
+        let picture = picture(16, 16, |x, y| if x < 8 { 90 } else { noise_at(x, y) });
+        let settings = CompSettings { big_square_size: 8, small_square_size: 4, split_depth: 1, split_mse: 10.0, ..CompSettings::default() };
+        let comp = compress(&picture, settings).unwrap();
+        let roots = square_grid(16, 16, 4);
+        let partition = roots.iter()
+            .flat_map(|r| if r.x < 8 { vec![Split::Leaf] } else { vec![Split::Quarters, Split::Leaf, Split::Leaf, Split::Leaf, Split::Leaf] })
+            .collect::<Vec<_>>();
+        assert_eq!(comp.partition, partition);
+        assert_eq!(comp.mapping.len(), 8 + 8 * 4);
+        assert!(comp.mapping.iter().all(|m| m.small.is_square() && m.small.width == if m.small.x < 8 { 4 } else { 2 }));
+        assert!(comp.mapping.iter().all(|m| m.big.width == 2 * m.small.width));
+        let restored = decompress(&comp, DecompSettings::default());
+        // flat up to the step of the quantized shift
+        let flat = restored[0][0];
+        assert!((flat as i32 - 90).abs() <= 2);
+        assert!(restored.iter().all(|row| row[..8].iter().all(|&p| p == flat)));
+    }
+
+    #[test]
+    fn hv_cuts_blocks_along_their_edges() {
+        // a bright bar three pixels into every 8x8 block
+        let picture = picture(16, 16, |x, y| if x % 8 < 3 { 220 } else { 20 + (y % 8) as u8 });
+        let settings = CompSettings { big_square_size: 16, small_square_size: 8, partition: Partition::Hv, split_depth: 1, split_mse: 1.0, ..CompSettings::default() };
+        let comp = compress(&picture, settings).unwrap();
+        assert_eq!(comp.partition.iter().filter(|&&s| s == Split::Vertical(3)).count(), 4);
+        assert!(comp.mapping.iter().all(|m| m.big.width == 2 * m.small.width && m.big.height == 2 * m.small.height));
+        assert!(comp.mapping.iter().all(|m| m.small.is_square() || !m.trans.transposes()));
+        let whole = compress(&picture, CompSettings { split_depth: 0, ..settings }).unwrap();
+        assert!(decoded_dist(&picture, &comp) < decoded_dist(&picture, &whole));
+    }
+
+    #[test]
+    fn overlapping_domains_match_texture_better() {
+        let picture = noise(32, 32).to_rows();
+        let settings = CompSettings { big_square_size: 8, small_square_size: 4, search: Search::Exhaustive, ..CompSettings::default() };
+        let (tiled, tiled_stats, _) = compress_with_stats(&picture, settings).unwrap();
+        let (overlapping, overlapping_stats, _) = compress_with_stats(&picture, CompSettings { domain_step: 2, ..settings }).unwrap();
+        // 13x13 instead of 4x4 domains
+        assert_eq!(overlapping_stats.compared, tiled_stats.compared / 16 * 169);
+        assert!(decoded_dist(&picture, &overlapping) < decoded_dist(&picture, &tiled));
+    }
+
+    #[test]
+    fn higher_bitrate_targets_decode_closer() {
+        let picture = picture(32, 32, |x, y| if y < 16 { 30 } else { ((x * x + 3 * y * y) / 4 % 256) as u8 });
+        let settings = CompSettings { big_square_size: 16, small_square_size: 8, split_depth: 2, precision_levels: 2, search: Search::Exhaustive, ..CompSettings::default() };
+        let low = compress(&picture, CompSettings { target: Some(Target::Bpp(1.0)), ..settings }).unwrap();
+        let high = compress(&picture, CompSettings { target: Some(Target::Bpp(4.0)), ..settings }).unwrap();
+        assert!(low.mapping.len() < high.mapping.len(), "{} vs {}", low.mapping.len(), high.mapping.len());
+        assert!(decoded_dist(&picture, &high) < decoded_dist(&picture, &low));
+    }
+
+    #[test]
+    fn decodes_at_any_size() {
+        let picture = picture(16, 16, |x, y| (x * 12 + y * 3) as u8);
+        let comp = compress(&picture, CompSettings { big_square_size: 8, small_square_size: 4, search: Search::Exhaustive, ..CompSettings::default() }).unwrap();
+        let decode = |size| decompress(&comp, DecompSettings { size, ..DecompSettings::default() });
+        let original = decode(OutputSize::Scaled(1.0));
+        let doubled = decode(OutputSize::Scaled(2.0));
+        assert_eq!((doubled[0].len(), doubled.len()), (32, 32));
+        assert!(doubled.scale_down(2).dist(&original) < 16 * 16);
+        let halved = decode(OutputSize::Scaled(0.5));
+        assert!(halved.dist(&original.scale_down(2)) < 8 * 8 * 4);
+        let stretched = decode(OutputSize::Exact { width: 20, height: 6 });
+        assert_eq!((stretched[0].len(), stretched.len()), (20, 6));
+    }
+
+    #[test]
+    fn decoding_stops_once_passes_stop_changing_the_image() {
+        let picture = picture(16, 16, |x, y| (x * 9 + y * 5 + x * y) as u8);
+        let comp = compress(&picture, CompSettings { big_square_size: 8, small_square_size: 4, max_factor: 0.5, ..CompSettings::default() }).unwrap();
+        // rounding keeps the last bits flickering, so it never stops by itself
+        let capped = DecompSettings { iterations: 200, converge_mse: 0.2, ..DecompSettings::default() };
+        let (settled, passes) = decompress_with_stats(&comp, capped);
+        assert!(passes < 20);
+        let full = decompress(&comp, DecompSettings { converge_mse: 0.0, ..capped });
+        assert!(settled.dist(&full) <= 16 * 16);
+        let (_, rough_passes) = decompress_with_stats(&comp, DecompSettings { converge_mse: 2.0, ..capped });
+        assert!(rough_passes < passes);
+        assert_eq!(decompress_with_stats(&comp, DecompSettings { iterations: 3, ..capped }).1, 3);
+    }
+
+    #[test]
+    fn in_place_decoding_settles_in_fewer_passes() {
+        let picture = picture(32, 32, |x, y| (x * 5 + y * 3 + x * y / 4) as u8);
+        let comp = compress(&picture, CompSettings { big_square_size: 8, small_square_size: 4, ..CompSettings::default() }).unwrap();
+        let settings = DecompSettings { iterations: 100, converge_mse: 0.2, ..DecompSettings::default() };
+        let (jacobi, jacobi_passes) = decompress_with_stats(&comp, settings);
+        let (in_place, in_place_passes) = decompress_with_stats(&comp, DecompSettings { update: Update::GaussSeidel, ..settings });
+        assert!(in_place_passes < jacobi_passes, "{} vs {} passes", in_place_passes, jacobi_passes);
+        assert!(in_place.dist(&jacobi) <= 32 * 32);
+    }
+
+    #[test]
+    fn pyramid_decoding_needs_few_full_size_passes() {
+        let picture = picture(64, 64, |x, y| (x * 5 + y * 3 + x * y / 4) as u8);
+        let comp = compress(&picture, CompSettings { big_square_size: 8, small_square_size: 4, ..CompSettings::default() }).unwrap();
+        let settled = decompress(&comp, DecompSettings { iterations: 50, ..DecompSettings::default() });
+        let flat = decompress(&comp, DecompSettings { iterations: 2, ..DecompSettings::default() });
+        let pyramid = decompress(&comp, DecompSettings { pyramid_levels: 2, ..DecompSettings::default() });
+        assert!(pyramid.dist(&settled) < flat.dist(&settled), "{} vs {}", pyramid.dist(&settled), flat.dist(&settled));
+    }
 }
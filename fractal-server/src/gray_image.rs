@@ -0,0 +1,279 @@
+use std::cmp;
+
+use byte_rect::*;
+use view::PixelSource;
+
+/// Single-channel image stored row by row in one contiguous buffer.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct GrayImage {
+    width: usize,
+    height: usize,
+    data: Vec<u8>,
+}
+
+/// Borrowed rectangle of a `GrayImage`: an offset and a stride into the
+/// parent's buffer, so taking one never copies pixels.
+#[derive(Debug, Clone, Copy)]
+pub struct GrayView<'a> {
+    data: &'a [u8],
+    offset: usize,
+    stride: usize,
+    width: usize,
+    height: usize,
+}
+
+impl GrayImage {
+    pub fn new(width: usize, height: usize, data: Vec<u8>) -> GrayImage {
+        assert_eq!(data.len(), width * height, "buffer does not match {}x{}", width, height);
+        GrayImage { width, height, data }
+    }
+
+    pub fn from_fn<F>(width: usize, height: usize, mut f: F) -> GrayImage where F: FnMut(usize, usize) -> u8 {
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                data.push(f(x, y));
+            }
+        }
+        GrayImage { width, height, data }
+    }
+
+    pub fn from_rows(rows: &[Vec<u8>]) -> GrayImage {
+        let width = rows.first().map_or(0, |r| r.len());
+        assert!(rows.iter().all(|r| r.len() == width), "rows differ in length");
+        GrayImage { width, height: rows.len(), data: rows.concat() }
+    }
+
+    pub fn to_rows(&self) -> Vec<Vec<u8>> {
+        self.data.chunks(self.width.max(1)).take(self.height).map(|r| r.to_vec()).collect()
+    }
+
+    pub fn as_raw(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn at(&self, x: usize, y: usize) -> u8 {
+        self.data[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: u8) {
+        self.data[y * self.width + x] = value;
+    }
+
+    pub fn row(&self, y: usize) -> &[u8] {
+        &self.data[y * self.width..(y + 1) * self.width]
+    }
+
+    pub fn as_view(&self) -> GrayView<'_> {
+        GrayView { data: &self.data, offset: 0, stride: self.width, width: self.width, height: self.height }
+    }
+
+    pub fn view(&self, x: usize, y: usize, width: usize, height: usize) -> GrayView<'_> {
+        self.as_view().view(x, y, width, height)
+    }
+
+    /// Copies `src` into this image with its top left corner at `(x, y)`.
+    pub fn paste(&mut self, x: usize, y: usize, src: &GrayImage) {
+        for src_y in 0..src.height {
+            let start = (y + src_y) * self.width + x;
+            self.data[start..start + src.width].copy_from_slice(src.row(src_y));
+        }
+    }
+}
+
+impl<'a> GrayView<'a> {
+    pub fn at(&self, x: usize, y: usize) -> u8 {
+        self.data[self.offset + y * self.stride + x]
+    }
+
+    pub fn row(&self, y: usize) -> &'a [u8] {
+        let start = self.offset + y * self.stride;
+        &self.data[start..start + self.width]
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &'a [u8]> + 'a {
+        let view = *self;
+        (0..self.height).map(move |y| view.row(y))
+    }
+
+    pub fn view(&self, x: usize, y: usize, width: usize, height: usize) -> GrayView<'a> {
+        assert!(x + width <= self.width && y + height <= self.height,
+            "{}x{} at ({}, {}) is outside of {}x{}", width, height, x, y, self.width, self.height);
+        GrayView { offset: self.offset + y * self.stride + x, width, height, ..*self }
+    }
+
+    pub fn to_image(self) -> GrayImage {
+        let mut data = Vec::with_capacity(self.width * self.height);
+        for row in self.rows() {
+            data.extend_from_slice(row);
+        }
+        GrayImage { width: self.width, height: self.height, data }
+    }
+
+    /// Scaled to `width x height`, every pixel being the mean of the ones it
+    /// covers, so a whole ratio gives the same as `ByteRect::scale_down`.
+    pub fn resize(&self, width: usize, height: usize) -> GrayImage {
+        let span = |i: usize, from: usize, to: usize| (i * from / to, ((i + 1) * from).div_ceil(to));
+        GrayImage::from_fn(width, height, |x, y| {
+            let (x0, x1) = span(x, self.width, width);
+            let (y0, y1) = span(y, self.height, height);
+            (self.view(x0, y0, x1 - x0, y1 - y0).sum() / ((x1 - x0) * (y1 - y0)) as i64) as u8
+        })
+    }
+}
+
+impl<'a> PixelSource for GrayView<'a> {
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn at(&self, x: usize, y: usize) -> u8 {
+        GrayView::at(self, x, y)
+    }
+
+    fn sum(&self) -> i64 {
+        self.rows().map(|r| r.iter().map(|&p| p as i64).sum::<i64>()).sum()
+    }
+
+    fn sqr_sum(&self) -> i64 {
+        self.rows().map(|r| r.iter().map(|&p| p as i64 * p as i64).sum::<i64>()).sum()
+    }
+}
+
+impl ByteRect for GrayImage {
+    fn width(&self) -> usize {
+        self.width
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn get_rect(&self, x: usize, y: usize, width: usize, height: usize) -> Self {
+        self.view(x, y, width, height).to_image()
+    }
+
+    fn transform(&self, t: Transform) -> Self {
+        let (new_width, new_height) = if t.transposes() { (self.height, self.width) } else { (self.width, self.height) };
+        GrayImage::from_fn(new_width, new_height, |x, y| {
+            let (src_x, src_y) = t.source_of(x, y, self.width, self.height);
+            self.at(src_x, src_y)
+        })
+    }
+
+    fn scale_down(&self, times: usize) -> Self {
+        if !self.height.is_multiple_of(times) || !self.width.is_multiple_of(times) {
+            panic!("Can only scale the multiples of {}", times)
+        }
+        GrayImage::from_fn(self.width / times, self.height / times, |x, y| {
+            (self.view(x * times, y * times, times, times).sum() / (times * times) as i64) as u8
+        })
+    }
+
+    fn linear(&self, c: LinearCoeffs) -> Self {
+        GrayImage {
+            data: self.data.iter().map(|&x| c.apply(x)).collect(),
+            ..*self
+        }
+    }
+
+    fn best_coeffs_to_match_within(&self, other: &Self, max_factor: f32) -> LinearCoeffs {
+        self.as_view().match_sums(&other.as_view()).coeffs(max_factor)
+    }
+
+    fn dist(&self, other: &Self) -> u64 {
+        self.as_view().dist(&other.as_view())
+    }
+
+    fn pad_to_divisible_by(&self, divisor: usize) -> Self {
+        let width = self.width.div_ceil(divisor) * divisor;
+        let height = self.height.div_ceil(divisor) * divisor;
+        GrayImage::from_fn(width, height, |x, y| self.at(cmp::min(x, self.width - 1), cmp::min(y, self.height - 1)))
+    }
+
+    fn to_square_chunks(&self, size: usize) -> Vec<(SquareCoords, Self)> {
+        if !self.height.is_multiple_of(size) || !self.width.is_multiple_of(size) {
+            panic!("can't chunk what is not divisible by chunk size");
+        }
+        square_grid(self.width, self.height, size)
+            .into_iter()
+            .map(|coords| (coords, self.get_square(coords)))
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use gray_image::*;
+
+    fn rows() -> Vec<Vec<u8>> {
+        (0..6).map(|y| (0..4).map(|x| (x * 37 + y * 11 + x * y * 5) as u8).collect()).collect()
+    }
+
+    #[test]
+    fn converts_from_and_to_rows() {
+        let img = GrayImage::from_rows(&rows());
+        assert_eq!((img.width(), img.height()), (4, 6));
+        assert_eq!(img.at(3, 5), rows()[5][3]);
+        assert_eq!(img.to_rows(), rows());
+    }
+
+    #[test]
+    fn views_share_the_parent_buffer() {
+        let img = GrayImage::from_rows(&rows());
+        let view = img.view(1, 2, 3, 3).view(1, 1, 2, 2);
+        assert_eq!(view.at(0, 0), rows()[3][2]);
+        assert_eq!(view.row(1), &rows()[4][2..4]);
+        assert_eq!(view.to_image().to_rows(), rows().get_rect(2, 3, 2, 2));
+    }
+
+    #[test]
+    fn byte_rect_matches_the_nested_vec_implementation() {
+        let vec = rows();
+        let img = GrayImage::from_rows(&vec);
+        for &t in Transform::ALL.iter() {
+            assert_eq!(img.transform(t).to_rows(), vec.transform(t), "{:?}", t);
+        }
+        assert_eq!(img.scale_down(2).to_rows(), vec.scale_down(2));
+        let coeffs = LinearCoeffs { shift: -20, factor: 0.7 };
+        assert_eq!(img.linear(coeffs).to_rows(), vec.linear(coeffs));
+        let other = vec.transform(Transform::HeadToBottom);
+        let other_img = GrayImage::from_rows(&other);
+        assert_eq!(img.best_coeffs_to_match(&other_img), vec.best_coeffs_to_match(&other));
+        assert_eq!(img.best_coeffs_to_match_within(&other_img, 0.5), vec.best_coeffs_to_match_within(&other, 0.5));
+        assert_eq!(img.dist(&other_img), vec.dist(&other));
+        assert_eq!(img.roughness(), vec.roughness());
+        assert_eq!(img.pad_to_divisible_by(5).to_rows(), vec.pad_to_divisible_by(5));
+        let chunks = img.to_square_chunks(2).into_iter().map(|(cs, ch)| (cs, ch.to_rows())).collect::<Vec<_>>();
+        assert_eq!(chunks, vec.to_square_chunks(2));
+    }
+
+    #[test]
+    fn paste_overwrites_a_rectangle() {
+        let mut img = GrayImage::from_fn(4, 4, |_, _| 0);
+        img.paste(1, 2, &GrayImage::from_fn(2, 2, |x, y| (1 + x + 2 * y) as u8));
+        assert_eq!(img.to_rows(), vec![
+            vec![0, 0, 0, 0],
+            vec![0, 0, 0, 0],
+            vec![0, 1, 2, 0],
+            vec![0, 3, 4, 0],
+        ]);
+    }
+
+    #[test]
+    fn resizing_averages_what_each_pixel_covers() {
+        let img = GrayImage::from_rows(&rows());
+        assert_eq!(img.as_view().resize(2, 3), img.scale_down(2));
+        let doubled = img.view(0, 0, 2, 2).resize(4, 4);
+        assert_eq!(doubled.at(1, 1), img.at(0, 0));
+        assert_eq!(doubled.at(3, 2), img.at(1, 1));
+        let stretched = img.view(0, 0, 3, 1).resize(2, 1);
+        assert_eq!(stretched.at(0, 0) as i64, (img.at(0, 0) as i64 + img.at(1, 0) as i64) / 2);
+        assert_eq!(stretched.at(1, 0) as i64, (img.at(1, 0) as i64 + img.at(2, 0) as i64) / 2);
+    }
+}
@@ -0,0 +1,164 @@
+// Horizontal-vertical partitioning after Fisher. A block that needs splitting
+// is cut in two along its strongest horizontal or vertical edge, preferring
+// edges near the middle, so the parts follow the image content instead of a
+// fixed grid. Range blocks of any shape are matched against the rectangles
+// twice their size on either axis.
+
+use byte_rect::*;
+use domain_pool::{dot, SearchStats};
+use gray_image::{GrayImage, GrayView};
+use partition::{Split, MIN_HV_SIDE};
+use quant::CoeffQuantizer;
+use view::PixelSource;
+
+/// Where the difference between neighbouring lines, weighted by how close
+/// they are to the middle, is largest, as `(at, strength)`.
+fn strongest_edge(line_sums: &[i64], line_len: usize) -> Option<(usize, f64)> {
+    let side = line_sums.len();
+    (MIN_HV_SIDE..=side.saturating_sub(MIN_HV_SIDE))
+        .map(|at| {
+            let step = (line_sums[at - 1] - line_sums[at]).abs() as f64 / line_len as f64;
+            (at, step * at.min(side - at) as f64 / side as f64)
+        })
+        .fold(None, |best: Option<(usize, f64)>, (at, strength)| match best {
+            Some(b) if b.1 >= strength => Some(b),
+            _ => Some((at, strength)),
+        })
+}
+
+/// Cuts `node` of `image` at its strongest edge, or through the middle of
+/// the longer side if it is flat. The node has to be splittable.
+pub fn cut(image: &GrayImage, node: RectCoords) -> Split {
+    let view = image.view(node.x, node.y, node.width, node.height);
+    let columns = (0..node.width).map(|x| view.rows().map(|row| row[x] as i64).sum()).collect::<Vec<i64>>();
+    let rows = view.rows().map(|row| row.iter().map(|&p| p as i64).sum()).collect::<Vec<i64>>();
+    let vertical = strongest_edge(&columns, node.height);
+    let horizontal = strongest_edge(&rows, node.width);
+    let (split, strength) = match (vertical, horizontal) {
+        (Some(v), Some(h)) if v.1 >= h.1 => (Split::Vertical(v.0), v.1),
+        (_, Some(h)) => (Split::Horizontal(h.0), h.1),
+        (Some(v), None) => (Split::Vertical(v.0), v.1),
+        (None, None) => panic!("{:?} is too small to cut", node),
+    };
+    if strength > 0.0 {
+        return split;
+    }
+    // nothing to follow, so halve the longer side that can be cut
+    if horizontal.is_none() || (vertical.is_some() && node.width >= node.height) {
+        Split::Vertical(node.width / 2)
+    } else {
+        Split::Horizontal(node.height / 2)
+    }
+}
+
+/// Transforms that keep the shape of a `width x height` block.
+fn shape_keeping(width: usize, height: usize) -> impl Iterator<Item = Transform> {
+    Transform::ALL.iter().cloned().filter(move |t| width == height || !t.transposes())
+}
+
+/// The domains of every shape: for range blocks of `width x height`, the
+/// `2 width x 2 height` rectangles of the image on a lattice of the given
+/// step, see `lattice_step`, read off a copy scaled to half the size. The
+/// step has to be even so the rectangles start on whole pixels of the copy.
+pub struct HvDomains {
+    half: GrayImage,
+    step: usize,
+}
+
+impl HvDomains {
+    pub fn new(padded: &GrayImage, step: usize) -> HvDomains {
+        assert!(step.is_multiple_of(2), "odd HV domain step {}", step);
+        HvDomains { half: padded.scale_down(2), step }
+    }
+
+    /// The domains for range blocks of `width x height`, with their scaled
+    /// down pixels.
+    fn tiles(&self, width: usize, height: usize) -> impl Iterator<Item = (RectCoords, GrayView<'_>)> {
+        let (step_x, step_y) = (lattice_step(self.step, 2 * width) / 2, lattice_step(self.step, 2 * height) / 2);
+        let count = |len: usize, side: usize, step: usize| if side <= len { (len - side) / step + 1 } else { 0 };
+        let cols = count(self.half.width(), width, step_x);
+        let rows = count(self.half.height(), height, step_y);
+        (0..rows).flat_map(move |row| (0..cols).map(move |col| {
+            let (x, y) = (col * step_x, row * step_y);
+            let big = RectCoords { x: 2 * x, y: 2 * y, width: 2 * width, height: 2 * height };
+            (big, self.half.view(x, y, width, height))
+        }))
+    }
+
+    /// The domain, transform and quantized coefficients closest to `range`
+    /// of `image` along with the sums they were fitted from, stopping at the
+    /// first one with a mean squared error of at most `accept_mse`.
+    pub fn best_match(&self, image: &GrayImage, range: RectCoords, quantizer: &CoeffQuantizer, accept_mse: f32)
+        -> ((RectCoords, Transform, LinearCoeffs, MatchSums), SearchStats) {
+        let view = image.view(range.x, range.y, range.width, range.height);
+        let (count, sum, sqr_sum) = ((range.width * range.height) as i64, view.sum(), view.sqr_sum());
+        let accept = accept_mse as f64 * count as f64;
+        // fitting the domain turned by `t` onto the range block is fitting
+        // the domain onto the range block turned back, which only needs
+        // turning once per transform
+        let turned_back = shape_keeping(range.width, range.height)
+            .map(|t| (t, image.get_rect_at(range).transform(t.inverse())))
+            .collect::<Vec<_>>();
+        let mut candidates = self.tiles(range.width, range.height)
+            .flat_map(|(big, domain)| {
+                let sums = (domain.sum(), domain.sqr_sum());
+                turned_back.iter().map(move |&(t, ref back)| (big, domain, sums, t, back))
+            });
+        let mut stats = SearchStats::default();
+        let mut best: Option<(RectCoords, Transform, LinearCoeffs, MatchSums, f64)> = None;
+        for (big, domain, (domain_sum, domain_sqr_sum), t, back) in candidates.by_ref() {
+            stats.compared += 1;
+            let sums = MatchSums {
+                count,
+                self_sum: domain_sum,
+                self_sqr_sum: domain_sqr_sum,
+                other_sum: sum,
+                prod_sum: dot(&domain, &back.as_view()),
+            };
+            let coeffs = quantizer.round(sums.coeffs(quantizer.max_factor));
+            let error = sums.sqr_error(coeffs, sqr_sum);
+            if best.is_none_or(|b| error < b.4) {
+                best = Some((big, t, coeffs, sums, error));
+            }
+            if error <= accept {
+                break;
+            }
+        }
+        stats.skipped = candidates.count();
+        let (big, t, coeffs, sums, _) = best.expect("no domain is twice the size of the range block");
+        ((big, t, coeffs, sums), stats)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use hv::*;
+    use testing::{exact_quantizer, noise};
+
+    #[test]
+    fn cuts_along_the_strongest_edge() {
+        let img = GrayImage::from_fn(8, 8, |x, _| if x < 3 { 10 } else { 200 });
+        let node = RectCoords { x: 0, y: 0, width: 8, height: 8 };
+        assert_eq!(cut(&img, node), Split::Vertical(3));
+        let img = GrayImage::from_fn(8, 8, |x, y| if y < 5 { 10 } else { 200 + x as u8 });
+        assert_eq!(cut(&img, node), Split::Horizontal(5));
+        let flat = GrayImage::from_fn(8, 4, |_, _| 50);
+        assert_eq!(cut(&flat, RectCoords { x: 0, y: 0, width: 8, height: 4 }), Split::Vertical(4));
+    }
+
+    #[test]
+    fn finds_an_exact_copy_of_a_rectangle() {
+        let img = noise(16, 16);
+        let domains = HvDomains::new(&img, 0);
+        let big = RectCoords { x: 8, y: 4, width: 8, height: 4 };
+        let target = img.get_rect_at(big).scale_down(2).transform(Transform::HeadToBottom);
+        let mut padded = img.clone();
+        padded.paste(0, 0, &target);
+        let range = RectCoords { x: 0, y: 0, width: 4, height: 2 };
+        let ((found, t, coeffs, _), stats) = domains.best_match(&padded, range, &exact_quantizer(), 0.0);
+        assert_eq!(img.get_rect_at(found).scale_down(2).transform(t), target);
+        assert_eq!(coeffs.factor, 1.0);
+        assert_eq!(stats.compared + stats.skipped, 2 * 4 * 4);
+    }
+}
@@ -0,0 +1,133 @@
+// Static k-d tree over fixed-length f32 points, built once and queried for
+// the k nearest points by euclidean distance.
+
+const LEAF_SIZE: usize = 8;
+
+pub struct KdTree {
+    dims: usize,
+    coords: Vec<f32>,
+    /// Point indices, arranged so every subrange is a subtree whose middle
+    /// element splits the rest along `axes[middle]`.
+    order: Vec<usize>,
+    axes: Vec<usize>,
+}
+
+impl KdTree {
+    /// `points` are consecutive `dims` long slices of `coords`.
+    pub fn new(dims: usize, coords: Vec<f32>) -> KdTree {
+        assert!(dims > 0 && coords.len().is_multiple_of(dims), "coordinates don't split into {} dimensions", dims);
+        let count = coords.len() / dims;
+        let mut tree = KdTree { dims, coords, order: (0..count).collect(), axes: vec![0; count] };
+        tree.build(0, count);
+        tree
+    }
+
+    fn point(&self, i: usize) -> &[f32] {
+        &self.coords[i * self.dims..(i + 1) * self.dims]
+    }
+
+    fn widest_axis(&self, lo: usize, hi: usize) -> usize {
+        (0..self.dims)
+            .map(|axis| {
+                let values = self.order[lo..hi].iter().map(|&i| self.point(i)[axis]);
+                let (min, max) = values.fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), v| (min.min(v), max.max(v)));
+                (axis, max - min)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap()
+            .0
+    }
+
+    fn build(&mut self, lo: usize, hi: usize) {
+        if hi - lo <= LEAF_SIZE {
+            return;
+        }
+        let axis = self.widest_axis(lo, hi);
+        let mid = lo + (hi - lo) / 2;
+        let (dims, coords) = (self.dims, &self.coords);
+        self.order[lo..hi].select_nth_unstable_by(mid - lo, |&a, &b| coords[a * dims + axis].total_cmp(&coords[b * dims + axis]));
+        self.axes[mid] = axis;
+        self.build(lo, mid);
+        self.build(mid + 1, hi);
+    }
+
+    /// Indices of the `k` points closest to `query` with their squared
+    /// distances, closest first.
+    pub fn nearest(&self, query: &[f32], k: usize) -> Vec<(usize, f32)> {
+        assert_eq!(query.len(), self.dims, "query has the wrong dimension");
+        let mut found = Vec::with_capacity(k.min(self.order.len()) + 1);
+        if k > 0 {
+            self.search(query, k, 0, self.order.len(), &mut found);
+        }
+        found
+    }
+
+    fn offer(&self, query: &[f32], k: usize, i: usize, found: &mut Vec<(usize, f32)>) {
+        let dist = self.point(i).iter().zip(query.iter()).map(|(&p, &q)| (p - q) * (p - q)).sum::<f32>();
+        if found.len() < k || dist < found[found.len() - 1].1 {
+            let at = found.iter().position(|&(_, d)| d > dist).unwrap_or(found.len());
+            found.insert(at, (i, dist));
+            found.truncate(k);
+        }
+    }
+
+    fn search(&self, query: &[f32], k: usize, lo: usize, hi: usize, found: &mut Vec<(usize, f32)>) {
+        if hi - lo <= LEAF_SIZE {
+            for &i in self.order[lo..hi].iter() {
+                self.offer(query, k, i, found);
+            }
+            return;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let split = self.order[mid];
+        let diff = query[self.axes[mid]] - self.point(split)[self.axes[mid]];
+        let (near, far) = if diff < 0.0 { ((lo, mid), (mid + 1, hi)) } else { ((mid + 1, hi), (lo, mid)) };
+        self.search(query, k, near.0, near.1, found);
+        self.offer(query, k, split, found);
+        if found.len() < k || diff * diff < found[found.len() - 1].1 {
+            self.search(query, k, far.0, far.1, found);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use kd_tree::*;
+
+    fn points(count: usize, dims: usize) -> Vec<f32> {
+        let mut state = 7u32;
+        (0..count * dims).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as f32 / 65536.0
+        }).collect()
+    }
+
+    fn brute_force(coords: &[f32], dims: usize, query: &[f32], k: usize) -> Vec<usize> {
+        let mut all = coords.chunks(dims)
+            .enumerate()
+            .map(|(i, p)| (i, p.iter().zip(query.iter()).map(|(&a, &b)| (a - b) * (a - b)).sum::<f32>()))
+            .collect::<Vec<_>>();
+        all.sort_by(|a, b| a.1.total_cmp(&b.1));
+        all.into_iter().take(k).map(|(i, _)| i).collect()
+    }
+
+    #[test]
+    fn finds_the_same_neighbours_as_brute_force() {
+        let dims = 5;
+        let coords = points(500, dims);
+        let tree = KdTree::new(dims, coords.clone());
+        for query in points(20, dims).chunks(dims) {
+            let found = tree.nearest(query, 7).into_iter().map(|(i, _)| i).collect::<Vec<_>>();
+            assert_eq!(found, brute_force(&coords, dims, query, 7));
+        }
+    }
+
+    #[test]
+    fn returns_everything_when_asked_for_more() {
+        let tree = KdTree::new(2, vec![0.0, 0.0, 1.0, 1.0, 5.0, 5.0]);
+        let found = tree.nearest(&[4.0, 4.0], 10);
+        assert_eq!(found.iter().map(|&(i, _)| i).collect::<Vec<_>>(), vec![2, 1, 0]);
+        assert_eq!(found[0].1, 2.0);
+    }
+}
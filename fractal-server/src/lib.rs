@@ -0,0 +1,29 @@
+//! Fractal (partitioned iterated function system) image compression.
+extern crate image;
+extern crate rayon;
+mod channel;
+mod byte_rect;
+mod gray_image;
+mod view;
+mod classify;
+mod domain_pool;
+mod kd_tree;
+mod nearest;
+mod search;
+mod partition;
+mod hv;
+mod rd;
+mod fractal;
+mod bits;
+mod quant;
+mod range_coder;
+pub mod container;
+pub mod codec;
+#[cfg(test)]
+mod testing;
+
+pub use fractal::{compress, compress_with_stats, decompress, decompress_with_stats, CompSettings, Compressed, DecompSettings, OutputSize, Update};
+pub use partition::Partition;
+pub use search::Search;
+pub use rd::{RateDistortion, Target};
+pub use domain_pool::SearchStats;
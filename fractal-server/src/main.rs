@@ -1,33 +1,110 @@
+extern crate fractal_server;
 extern crate image;
-mod channel;
-mod byte_rect;
-mod fractal;
-use std::path::Path;
+extern crate tiny_http;
+mod cli;
+mod server;
+use std::env;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::process;
 
-use image::GenericImage;
+use fractal_server::{codec, container, DecompSettings, RateDistortion, SearchStats, Target};
 
-fn encode_and_decode_ch(ch: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
-    let c = fractal::compress(&ch, fractal::CompSettings { small_square_size: 4, big_square_size: 16, grouping_factor: 20 });
-    fractal::decompress(&c, fractal::DecompSettings { iterations: 20 })
+use cli::Command;
+
+fn bpp(bytes: usize, pixels: usize) -> f64 {
+    bytes as f64 * 8.0 / pixels as f64
+}
+
+fn report_size(width: usize, height: usize, comp_size: usize) {
+    println!("compressed {}x{} image into {} bytes ({:.3} bpp)",
+        width, height, comp_size, bpp(comp_size, width * height));
+}
+
+/// Tells when the image came out bigger or worse than `target` asked for,
+/// which happens when no coding the encoder knows reaches it.
+fn report_missed_target(target: Option<Target>, bpp: f64, psnr: Option<f64>) {
+    match (target, psnr) {
+        (Some(Target::Bpp(wanted)), _) if bpp > wanted as f64 =>
+            println!("target of {} bpp unreachable, achieved {:.3} bpp", wanted, bpp),
+        (Some(Target::Psnr(wanted)), Some(psnr)) if psnr < wanted as f64 =>
+            println!("target of {} dB unreachable, achieved {:.3} dB", wanted, psnr),
+        _ => (),
+    }
+}
+
+fn report_encoding(stats: SearchStats, estimates: &[RateDistortion]) {
+    println!("{}", stats);
+    for estimate in estimates.iter() {
+        println!("channel estimated at {:.3} bpp and {:.2} dB", estimate.bpp(), estimate.psnr());
+    }
+}
+
+fn run(cmd: Command) -> Result<(), Box<dyn Error>> {
+    match cmd {
+        Command::Encode { input, output, settings, coding } => {
+            let img = image::open(&input)?;
+            let (compressed, stats, estimates) = codec::compress_image_with_stats(&img, settings)?;
+            report_encoding(stats, &estimates);
+            container::write(&compressed, coding, &mut BufWriter::new(File::create(&output)?))?;
+            let size = fs::metadata(&output)?.len() as usize;
+            report_size(compressed[0].orig_width, compressed[0].orig_height, size);
+            if settings.target.is_some() {
+                // what the target achieved, as `decode` will see it
+                let restored = codec::decompress_image(&compressed, DecompSettings::default())?;
+                let psnr = codec::psnr(&img.to_rgb8(), &restored);
+                println!("PSNR {:.3} dB", psnr);
+                report_missed_target(settings.target, bpp(size, (img.width() * img.height()) as usize), Some(psnr));
+            }
+        }
+        Command::Decode { input, output, settings } => {
+            let compressed = container::read(&mut BufReader::new(File::open(&input)?))?;
+            let (restored, passes) = codec::decompress_image_with_stats(&compressed, settings)?;
+            restored.save(&output)?;
+            println!("decoded in {} passes", passes);
+        }
+        Command::Roundtrip { input, output, comp, decomp, coding } => {
+            let img = image::open(&input)?;
+            let mut bytes = Vec::new();
+            let (compressed, stats, estimates) = codec::compress_image_with_stats(&img, comp)?;
+            report_encoding(stats, &estimates);
+            container::write(&compressed, coding, &mut bytes)?;
+            let compressed = container::read(&mut &bytes[..])?;
+            let (restored, passes) = codec::decompress_image_with_stats(&compressed, decomp)?;
+            println!("input file takes {} bytes", fs::metadata(&input)?.len());
+            println!("decoded in {} passes", passes);
+            report_size(img.width() as usize, img.height() as usize, bytes.len());
+            // a zoomed decoding has nothing to be compared with
+            let psnr = if restored.dimensions() == (img.width(), img.height()) {
+                Some(codec::psnr(&img.to_rgb8(), &restored))
+            } else {
+                None
+            };
+            if let Some(psnr) = psnr {
+                println!("PSNR {:.3} dB", psnr);
+            }
+            report_missed_target(comp.target, bpp(bytes.len(), (img.width() * img.height()) as usize), psnr);
+            if let Some(output) = output {
+                restored.save(&output)?;
+            }
+        }
+        Command::Serve { addr } => server::serve(&addr)?,
+    }
+    Ok(())
 }
 
 fn main() {
-    let img = image::open(&Path::new("in.png")).unwrap();
-    let (width, height) = img.dimensions();
-    let rgb = (0..height)
-        .map(|y|
-            (0..width)
-                .map(|x| img.get_pixel(x, y).data)
-                .map(|px| channel::RgbPx { r: px[0], g: px[1], b: px[2] })
-                .collect::<Vec<_>>())
-        .collect::<Vec<_>>();
-    let (rs, gs, bs) = channel::to_rgb_channels(&rgb);
-    let (rs_p, gs_p, bs_p) = (
-        encode_and_decode_ch(rs), encode_and_decode_ch(gs), encode_and_decode_ch(bs)
-    );
-    let res_img = image::ImageBuffer::from_fn(width, height, |x_i, y_i| {
-        let (x, y) = (x_i as usize, y_i as usize);
-        image::Rgb([rs_p[y][x], gs_p[y][x], bs_p[y][x]])
-    });
-    let _ = res_img.save(&Path::new("out.png")).unwrap();
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let cmd = match cli::parse(&args) {
+        Ok(cmd) => cmd,
+        Err(msg) => {
+            eprintln!("{}\n\n{}", msg, cli::USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(err) = run(cmd) {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,125 @@
+// Saupe's nearest-neighbour search. A block minus its mean, scaled to unit
+// length, is a point on a sphere; the least squares error of fitting a domain
+// onto a range shrinks as their points (or the domain's mirrored one, for a
+// negative factor) get closer. So instead of fitting every domain, only the
+// ones nearest to the range in this space get the exact fit.
+
+use byte_rect::{LinearCoeffs, Transform};
+use domain_pool::{DomainPool, RangeBlock, SearchStats};
+use kd_tree::KdTree;
+use quant::CoeffQuantizer;
+use search::SearchStrategy;
+use view::PixelSource;
+
+/// Blocks are averaged down to at most this many cells per side first, which
+/// keeps the tree shallow enough to prune for large blocks.
+const FEATURE_SIDE: usize = 4;
+
+/// Zero-mean, unit-length cell averages of `block`; all zeroes for a flat one.
+pub fn features<S: PixelSource>(block: &S) -> Vec<f32> {
+    let (width, height) = (block.width(), block.height());
+    let (cols, rows) = (width.min(FEATURE_SIDE), height.min(FEATURE_SIDE));
+    let mut cells = vec![(0u32, 0u32); cols * rows];
+    for y in 0..height {
+        for x in 0..width {
+            let cell = &mut cells[(y * rows / height) * cols + x * cols / width];
+            cell.0 += block.at(x, y) as u32;
+            cell.1 += 1;
+        }
+    }
+    let mut values = cells.iter().map(|&(sum, count)| sum as f32 / count as f32).collect::<Vec<_>>();
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    let norm = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>().sqrt();
+    for v in values.iter_mut() {
+        *v = if norm > 0.0 { (*v - mean) / norm } else { 0.0 };
+    }
+    values
+}
+
+/// Feature points of every domain of a pool in all isometries, both as they
+/// are and negated.
+pub struct NearestDomains {
+    /// How many of the nearest points get the exact fit.
+    k: usize,
+    tree: KdTree,
+    /// Domain index and transform of every point in the tree.
+    points: Vec<(usize, Transform)>,
+}
+
+impl NearestDomains {
+    pub fn new(pool: &DomainPool, k: usize) -> NearestDomains {
+        let mut coords = Vec::new();
+        let mut points = Vec::new();
+        let mut dims = 1;
+        for (i, domain) in pool.domains().iter().enumerate() {
+            for &t in Transform::ALL.iter() {
+                let f = features(&domain.isometry(t).as_view());
+                dims = f.len();
+                coords.extend(f.iter().cloned());
+                coords.extend(f.iter().map(|v| -v));
+                points.push((i, t));
+                points.push((i, t));
+            }
+        }
+        // there's no use asking for more neighbours than there are points
+        NearestDomains { k: k.min(points.len()), tree: KdTree::new(dims, coords), points }
+    }
+}
+
+impl SearchStrategy for NearestDomains {
+    /// Like `DomainPool::best_match`, but only fits the `k` candidates
+    /// nearest to `range` in feature space.
+    fn best_match(&self, pool: &DomainPool, range: &RangeBlock, quantizer: &CoeffQuantizer, accept_mse: f32) -> ((usize, Transform, LinearCoeffs), SearchStats) {
+        let mut candidates = Vec::with_capacity(self.k);
+        for (p, _) in self.tree.nearest(&features(&range.view), self.k) {
+            // a domain can come up both as is and negated
+            if !candidates.contains(&self.points[p]) {
+                candidates.push(self.points[p]);
+            }
+        }
+        pool.best_of_or_all(candidates.into_iter(), range, quantizer, accept_mse)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use nearest::*;
+    use byte_rect::{ByteRect, SquareCoords};
+    use gray_image::GrayImage;
+    use testing::noise;
+
+    #[test]
+    fn features_ignore_brightness_and_contrast() {
+        let a = GrayImage::from_fn(8, 8, |x, y| (x * 3 + y * 7) as u8);
+        let b = GrayImage::from_fn(8, 8, |x, y| (100 + 2 * (x * 3 + y * 7)) as u8);
+        let (fa, fb) = (features(&a.as_view()), features(&b.as_view()));
+        assert_eq!(fa.len(), 16);
+        assert!(fa.iter().zip(fb.iter()).all(|(p, q)| (p - q).abs() < 1e-5));
+        assert!((fa.iter().map(|v| v * v).sum::<f32>() - 1.0).abs() < 1e-5);
+        assert!(features(&GrayImage::from_fn(4, 4, |_, _| 9).as_view()).iter().all(|&v| v == 0.0));
+    }
+
+    #[test]
+    fn finds_a_scaled_copy_of_a_domain() {
+        let img = noise(16, 16);
+        let pool = DomainPool::new(&img, 8, 4);
+        let nearest = NearestDomains::new(&pool, 1);
+        let target = pool.domains()[2].isometry(Transform::HeadToLeftInv).linear(LinearCoeffs { shift: 140, factor: -0.5 });
+        let range = RangeBlock::new(&target, SquareCoords { x: 0, y: 0, side: 4 });
+        let ((i, t, _), _) = nearest.best_match(&pool, &range, &CoeffQuantizer::default(), 0.0);
+        assert_eq!(pool.domains()[i].isometry(t), pool.domains()[2].isometry(Transform::HeadToLeftInv));
+    }
+
+    #[test]
+    fn huge_k_takes_every_point() {
+        let img = noise(16, 16);
+        let pool = DomainPool::new(&img, 8, 4);
+        let nearest = NearestDomains::new(&pool, 100_000_000_000_000);
+        assert_eq!(nearest.k, nearest.points.len());
+        let target = pool.domains()[1].isometry(Transform::HeadToBottom);
+        let range = RangeBlock::new(target, SquareCoords { x: 0, y: 0, side: 4 });
+        let ((i, t, _), _) = nearest.best_match(&pool, &range, &CoeffQuantizer::default(), 0.0);
+        assert_eq!(pool.domains()[i].isometry(t), target);
+    }
+}
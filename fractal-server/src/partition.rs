@@ -0,0 +1,172 @@
+// Partitions of the range grid. Every square of the regular range grid is
+// the root of a tree whose nodes are either range blocks or split, at most
+// `depth` times along any path: into four quarters for a quadtree, or in two
+// at a cut for HV. A partition is stored as the split of every node in
+// pre-order, and the range blocks come in the same order.
+
+use byte_rect::{RectCoords, SquareCoords};
+use gray_image::GrayImage;
+use hv;
+
+/// How range blocks whose best match is not good enough are split.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Partition {
+    /// Into four squares of half the side.
+    Quadtree,
+    /// Into two rectangles along the strongest edge, see `hv`.
+    Hv,
+}
+
+/// Most splits allowed along any path, enough to halve both sides of the
+/// largest image the container takes down to a pixel.
+pub const MAX_SPLIT_DEPTH: usize = 32;
+
+/// Narrowest a part of an HV cut may be.
+pub const MIN_HV_SIDE: usize = 2;
+
+/// Whether a side is long enough for an HV cut.
+pub fn can_cut(side: usize) -> bool {
+    side >= 2 * MIN_HV_SIDE
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Split {
+    /// The node is a range block.
+    Leaf,
+    Quarters,
+    /// Into a left part `at` pixels wide and the rest.
+    Vertical(usize),
+    /// Into a top part `at` pixels high and the rest.
+    Horizontal(usize),
+}
+
+impl Split {
+    /// The children of `node`, in reading order.
+    pub fn parts(self, node: RectCoords) -> Vec<RectCoords> {
+        let RectCoords { x, y, width, height } = node;
+        match self {
+            Split::Leaf => vec![node],
+            Split::Quarters => {
+                let (w, h) = (width / 2, height / 2);
+                let at = |dx, dy| RectCoords { x: x + dx, y: y + dy, width: w, height: h };
+                vec![at(0, 0), at(w, 0), at(0, h), at(w, h)]
+            }
+            Split::Vertical(at) => vec![
+                RectCoords { width: at, ..node },
+                RectCoords { x: x + at, width: width - at, ..node },
+            ],
+            Split::Horizontal(at) => vec![
+                RectCoords { height: at, ..node },
+                RectCoords { y: y + at, height: height - at, ..node },
+            ],
+        }
+    }
+}
+
+impl Partition {
+    /// Whether `node` may be split at all with `depth` more splits allowed.
+    /// Only such nodes have their split stored.
+    pub fn can_split(self, node: RectCoords, depth: usize) -> bool {
+        depth > 0 && match self {
+            Partition::Quadtree => node.width.is_multiple_of(2) && node.height.is_multiple_of(2),
+            Partition::Hv => can_cut(node.width) || can_cut(node.height),
+        }
+    }
+
+    /// Whether this partition may split `node` by `split`.
+    pub fn allows(self, node: RectCoords, depth: usize, split: Split) -> bool {
+        let cut_fits = |at: usize, side: usize| at >= MIN_HV_SIDE && at + MIN_HV_SIDE <= side;
+        match (self, split) {
+            (_, Split::Leaf) => true,
+            _ if !self.can_split(node, depth) => false,
+            (Partition::Quadtree, Split::Quarters) => true,
+            (Partition::Hv, Split::Vertical(at)) => cut_fits(at, node.width),
+            (Partition::Hv, Split::Horizontal(at)) => cut_fits(at, node.height),
+            _ => false,
+        }
+    }
+
+    /// How the encoder splits `node` of `image`, if it may.
+    pub fn split(self, image: &GrayImage, node: RectCoords, depth: usize) -> Option<Split> {
+        if !self.can_split(node, depth) {
+            return None;
+        }
+        Some(match self {
+            Partition::Quadtree => Split::Quarters,
+            Partition::Hv => hv::cut(image, node),
+        })
+    }
+}
+
+/// Range blocks of the partition of `roots` whose splits `next_split` hands
+/// out node by node, in pre-order. It gets each node with the number of
+/// splits still allowed below it, and may only return splits `allows` them.
+pub fn leaves<E, F>(roots: &[SquareCoords], depth: usize, mut next_split: F) -> Result<Vec<RectCoords>, E>
+    where F: FnMut(RectCoords, usize) -> Result<Split, E> {
+    let mut leaves = Vec::new();
+    // nodes still to visit, the next one on top
+    let mut stack = Vec::new();
+    for &root in roots.iter() {
+        stack.push((root.into(), depth));
+        while let Some((node, depth)) = stack.pop() {
+            match next_split(node, depth)? {
+                Split::Leaf => leaves.push(node),
+                split => stack.extend(split.parts(node).into_iter().rev().map(|part| (part, depth - 1))),
+            }
+        }
+    }
+    Ok(leaves)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use partition::*;
+    use byte_rect::square_grid;
+
+    fn rect(x: usize, y: usize, width: usize, height: usize) -> RectCoords {
+        RectCoords { x, y, width, height }
+    }
+
+    #[test]
+    fn parts_tile_the_node() {
+        let node = rect(8, 4, 4, 6);
+        assert_eq!(Split::Quarters.parts(node), vec![rect(8, 4, 2, 3), rect(10, 4, 2, 3), rect(8, 7, 2, 3), rect(10, 7, 2, 3)]);
+        assert_eq!(Split::Vertical(3).parts(node), vec![rect(8, 4, 3, 6), rect(11, 4, 1, 6)]);
+        assert_eq!(Split::Horizontal(2).parts(node), vec![rect(8, 4, 4, 2), rect(8, 6, 4, 4)]);
+    }
+
+    #[test]
+    fn splits_are_read_in_pre_order() {
+        let roots = square_grid(16, 8, 8);
+        let mut splits = vec![Split::Quarters, Split::Leaf, Split::Quarters, Split::Leaf, Split::Leaf, Split::Leaf,
+            Split::Leaf, Split::Leaf, Split::Leaf, Split::Vertical(2), Split::Leaf, Split::Leaf].into_iter();
+        let leaves = leaves(&roots, 2, |_, _| splits.next().ok_or(())).unwrap();
+        assert!(splits.next().is_none());
+        let widths = leaves.iter().map(|l| l.width).collect::<Vec<_>>();
+        assert_eq!(widths, vec![4, 2, 2, 2, 2, 4, 4, 2, 6]);
+        assert_eq!(leaves[1], rect(4, 0, 2, 2));
+        assert_eq!(leaves[8], rect(10, 0, 6, 8));
+    }
+
+    #[test]
+    fn deep_partitions_do_not_exhaust_the_stack() {
+        let roots = square_grid(1 << 16, 1 << 16, 1 << 16);
+        let leaves = leaves(&roots, usize::MAX, |node, _| Ok::<_, ()>(if can_cut(node.width) { Split::Vertical(2) } else { Split::Leaf })).unwrap();
+        assert_eq!(leaves.len(), 1 << 15);
+        assert_eq!(leaves[0], rect(0, 0, 2, 1 << 16));
+    }
+
+    #[test]
+    fn cuts_leave_room_on_both_sides() {
+        let node = rect(0, 0, 8, 3);
+        assert!(Partition::Hv.allows(node, 1, Split::Vertical(2)));
+        assert!(Partition::Hv.allows(node, 1, Split::Vertical(6)));
+        assert!(!Partition::Hv.allows(node, 1, Split::Vertical(7)));
+        assert!(!Partition::Hv.allows(node, 1, Split::Horizontal(1)));
+        assert!(!Partition::Hv.allows(node, 0, Split::Vertical(4)));
+        assert!(!Partition::Hv.allows(node, 1, Split::Quarters));
+        assert!(Partition::Quadtree.allows(rect(0, 0, 4, 4), 1, Split::Quarters));
+        assert!(!Partition::Quadtree.allows(rect(0, 0, 4, 4), 1, Split::Vertical(2)));
+    }
+}
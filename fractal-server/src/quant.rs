@@ -0,0 +1,86 @@
+use byte_rect::LinearCoeffs;
+
+/// Maps `LinearCoeffs` to a pair of fixed-width integers and back.
+///
+/// The factor is stored in `factor_bits` bits uniformly covering
+/// `[-max_factor, max_factor)`, so that 0 and simple halves are exact.
+/// The shift covers every value that can still produce a visible pixel for
+/// such a factor, i.e. `[-255 * max_factor, 255 * (1 + max_factor)]`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CoeffQuantizer {
+    pub factor_bits: u8,
+    pub shift_bits: u8,
+    pub max_factor: f32,
+}
+
+impl Default for CoeffQuantizer {
+    fn default() -> CoeffQuantizer {
+        CoeffQuantizer { factor_bits: 5, shift_bits: 7, max_factor: 1.0 }
+    }
+}
+
+impl CoeffQuantizer {
+    fn shift_range(&self) -> (f32, f32) {
+        (-255.0 * self.max_factor, 255.0 * (1.0 + self.max_factor))
+    }
+
+    pub fn quantize(&self, c: LinearCoeffs) -> (u32, u32) {
+        let factor_levels = 1u32 << self.factor_bits;
+        let half = (factor_levels / 2) as f32;
+        let factor_q = (c.factor / self.max_factor * half + half)
+            .round()
+            .clamp(0.0, (factor_levels - 1) as f32) as u32;
+        let shift_levels = 1u32 << self.shift_bits;
+        let (min, max) = self.shift_range();
+        let shift_q = ((c.shift as f32 - min) / (max - min) * (shift_levels - 1) as f32)
+            .round()
+            .clamp(0.0, (shift_levels - 1) as f32) as u32;
+        (factor_q, shift_q)
+    }
+
+    pub fn dequantize(&self, factor_q: u32, shift_q: u32) -> LinearCoeffs {
+        let half = ((1u32 << self.factor_bits) / 2) as f32;
+        let shift_levels = 1u32 << self.shift_bits;
+        let (min, max) = self.shift_range();
+        LinearCoeffs {
+            shift: (min + shift_q as f32 * (max - min) / (shift_levels - 1) as f32).round() as i16,
+            factor: (factor_q as f32 - half) * self.max_factor / half,
+        }
+    }
+
+    /// The coefficients the decoder will actually see after a quantization round trip.
+    pub fn round(&self, c: LinearCoeffs) -> LinearCoeffs {
+        let (factor_q, shift_q) = self.quantize(c);
+        self.dequantize(factor_q, shift_q)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use quant::*;
+
+    #[test]
+    fn zero_and_halves_survive_quantization() {
+        let q = CoeffQuantizer::default();
+        for &factor in [0.0, 0.5, -0.5, -1.0].iter() {
+            assert_eq!(q.round(LinearCoeffs { shift: 0, factor }).factor, factor);
+        }
+    }
+
+    #[test]
+    fn out_of_range_values_are_clamped() {
+        let q = CoeffQuantizer::default();
+        let LinearCoeffs { shift, factor } = q.round(LinearCoeffs { shift: 2000, factor: 3.0 });
+        assert_eq!(shift, 510);
+        assert_eq!(factor, 1.0 - 1.0 / 16.0);
+    }
+
+    #[test]
+    fn fine_enough_shift_step_keeps_integers_exact() {
+        let q = CoeffQuantizer { factor_bits: 5, shift_bits: 10, max_factor: 1.0 };
+        for shift in -255..511 {
+            assert_eq!(q.round(LinearCoeffs { shift, factor: 0.0 }).shift, shift);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+// Adaptive binary range coder in the style of LZMA: every coded bit has a
+// probability slot that learns from the bits already seen, multi-bit values
+// are coded through a binary tree of such slots.
+
+const PROB_BITS: u32 = 11;
+const PROB_INIT: u16 = 1 << (PROB_BITS - 1);
+const MOVE_BITS: u32 = 5;
+const TOP: u32 = 1 << 24;
+
+pub struct Encoder {
+    low: u64,
+    range: u32,
+    cache: u8,
+    cache_size: u64,
+    out: Vec<u8>,
+}
+
+impl Default for Encoder {
+    fn default() -> Encoder {
+        Encoder { low: 0, range: u32::MAX, cache: 0, cache_size: 1, out: Vec::new() }
+    }
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder::default()
+    }
+
+    fn shift_low(&mut self) {
+        if self.low < 0xff00_0000 || self.low > u32::MAX as u64 {
+            let carry = (self.low >> 32) as u8;
+            let mut pending = self.cache;
+            while self.cache_size > 0 {
+                self.out.push(pending.wrapping_add(carry));
+                pending = 0xff;
+                self.cache_size -= 1;
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00ff_ffff) << 8;
+    }
+
+    pub fn encode_bit(&mut self, prob: &mut u16, bit: u32) {
+        let bound = (self.range >> PROB_BITS) * *prob as u32;
+        if bit == 0 {
+            self.range = bound;
+            *prob += ((1 << PROB_BITS) - *prob) >> MOVE_BITS;
+        } else {
+            self.low += bound as u64;
+            self.range -= bound;
+            *prob -= *prob >> MOVE_BITS;
+        }
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        self.out
+    }
+}
+
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    range: u32,
+    code: u32,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Decoder<'a> {
+        let mut dec = Decoder { bytes, pos: 0, range: u32::MAX, code: 0 };
+        for _ in 0..5 {
+            dec.code = (dec.code << 8) | dec.next_byte() as u32;
+        }
+        dec
+    }
+
+    // The encoder flushes every byte that matters, past the end only zeroes are implied.
+    fn next_byte(&mut self) -> u8 {
+        let byte = self.bytes.get(self.pos).cloned().unwrap_or(0);
+        self.pos += 1;
+        byte
+    }
+
+    pub fn decode_bit(&mut self, prob: &mut u16) -> u32 {
+        let bound = (self.range >> PROB_BITS) * *prob as u32;
+        let bit = if self.code < bound {
+            self.range = bound;
+            *prob += ((1 << PROB_BITS) - *prob) >> MOVE_BITS;
+            0
+        } else {
+            self.code -= bound;
+            self.range -= bound;
+            *prob -= *prob >> MOVE_BITS;
+            1
+        };
+        while self.range < TOP {
+            self.range <<= 8;
+            self.code = (self.code << 8) | self.next_byte() as u32;
+        }
+        bit
+    }
+}
+
+const TREE_BITS: u8 = 8;
+
+/// Adaptive model for a value of fixed bit width. The top `TREE_BITS` bits
+/// are coded with a full binary context tree, so their joint distribution is
+/// learned; any bits below that only get one context per bit position.
+pub struct ValueModel {
+    bits: u8,
+    tree: Vec<u16>,
+    low: Vec<u16>,
+}
+
+impl ValueModel {
+    pub fn new(bits: u8) -> ValueModel {
+        let tree_bits = bits.min(TREE_BITS);
+        ValueModel {
+            bits,
+            tree: vec![PROB_INIT; 1 << tree_bits],
+            low: vec![PROB_INIT; (bits - tree_bits) as usize],
+        }
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    pub fn encode(&mut self, enc: &mut Encoder, value: u32) {
+        let low_bits = self.low.len();
+        let mut node = 1;
+        for i in (low_bits..self.bits as usize).rev() {
+            let bit = (value >> i) & 1;
+            enc.encode_bit(&mut self.tree[node], bit);
+            node = node * 2 + bit as usize;
+        }
+        for i in (0..low_bits).rev() {
+            enc.encode_bit(&mut self.low[i], (value >> i) & 1);
+        }
+    }
+
+    pub fn decode(&mut self, dec: &mut Decoder) -> u32 {
+        let low_bits = self.low.len();
+        let mut node = 1;
+        for _ in low_bits..self.bits as usize {
+            node = node * 2 + dec.decode_bit(&mut self.tree[node]) as usize;
+        }
+        let mut value = (node - (1 << (self.bits as usize - low_bits))) as u32;
+        for i in (0..low_bits).rev() {
+            value = (value << 1) | dec.decode_bit(&mut self.low[i]);
+        }
+        value
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use range_coder::*;
+
+    fn roundtrip(bits: u8, values: &[u32]) -> usize {
+        let mut enc = Encoder::new();
+        let mut model = ValueModel::new(bits);
+        for &v in values.iter() {
+            model.encode(&mut enc, v);
+        }
+        let bytes = enc.finish();
+        let mut dec = Decoder::new(&bytes);
+        let mut model = ValueModel::new(bits);
+        for &v in values.iter() {
+            assert_eq!(model.decode(&mut dec), v);
+        }
+        bytes.len()
+    }
+
+    #[test]
+    fn decodes_what_was_encoded() {
+        let values = (0..1000u32).map(|i| i * 7919 % 1024).collect::<Vec<_>>();
+        roundtrip(10, &values);
+        roundtrip(3, &values.iter().map(|v| v % 8).collect::<Vec<_>>());
+        roundtrip(14, &values.iter().map(|v| v * 13).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn zero_width_values_cost_nothing() {
+        assert_eq!(roundtrip(0, &[0; 100]), 5);
+    }
+
+    #[test]
+    fn skewed_values_take_far_less_than_their_width() {
+        let values = (0..1000u32).map(|i| if i % 10 == 0 { 37 } else { 5 }).collect::<Vec<_>>();
+        let len = roundtrip(8, &values);
+        assert!(len < 1000 / 8, "took {} bytes", len);
+    }
+}
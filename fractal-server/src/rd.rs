@@ -0,0 +1,228 @@
+// Rate-distortion optimised encoding. Instead of splitting every block whose
+// match is worse than `split_mse`, the encoder works out every way of coding
+// each square of the range grid: as one range block at every coefficient
+// precision, or split into parts coded the same way. For a Lagrange
+// multiplier `lambda` every node then takes whichever costs the least
+// squared error plus `lambda` per bit, and `lambda` is bisected until the
+// chosen coding meets the target.
+//
+// Rates are counted as `Coding::Packed` stores the mapping with every domain
+// as a lattice index, plus the headers around it, so the file usually ends up
+// smaller. Distortions are the collage errors of the matches, which decoding
+// only approaches.
+
+use fractal::SquareMapping;
+use partition::Split;
+
+/// What a rate-distortion optimised encoding aims at.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Target {
+    /// At most this many bits per pixel.
+    Bpp(f32),
+    /// At least this peak signal-to-noise ratio in dB.
+    Psnr(f32),
+}
+
+impl Target {
+    /// The target for each of `channels` channels encoded independently,
+    /// which share the bits but all have to reach the PSNR. Every channel
+    /// counts `shared_bpp` the file only has once.
+    pub fn per_channel(self, channels: usize, shared_bpp: f32) -> Target {
+        match self {
+            Target::Bpp(bpp) => Target::Bpp((bpp + shared_bpp * (channels - 1) as f32) / channels as f32),
+            Target::Psnr(psnr) => Target::Psnr(psnr),
+        }
+    }
+}
+
+/// Estimated size and error of a coding of `pixels` pixels.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RateDistortion {
+    pub bits: usize,
+    pub sqr_error: f64,
+    pub pixels: usize,
+}
+
+impl RateDistortion {
+    pub fn bpp(&self) -> f64 {
+        self.bits as f64 / self.pixels as f64
+    }
+
+    pub fn psnr(&self) -> f64 {
+        10.0 * (255.0 * 255.0 * self.pixels as f64 / self.sqr_error).log10()
+    }
+
+    /// Whether a coding this big and this far off reaches `target`.
+    pub fn meets(&self, target: Target) -> bool {
+        match target {
+            Target::Bpp(bpp) => self.bpp() <= bpp as f64,
+            Target::Psnr(psnr) => self.psnr() >= psnr as f64,
+        }
+    }
+}
+
+/// Every way of coding a node of the partition.
+pub struct Candidate {
+    /// The node as one range block, at every coarseness: its mapping, the
+    /// bits it takes and its squared error.
+    pub leaves: Vec<(SquareMapping, usize, f64)>,
+    /// Bits telling a leaf from a split node, if the node could be split.
+    pub leaf_bits: usize,
+    /// How the encoder would split the node, the bits that takes and its
+    /// parts.
+    pub split: Option<(Split, usize, Vec<Candidate>)>,
+}
+
+impl Candidate {
+    /// Bits, squared error and Lagrangian cost of the cheapest coding.
+    fn cheapest(&self, lambda: f64) -> (usize, f64, f64) {
+        let (i, leaf_cost) = self.cheapest_leaf(lambda);
+        let (_, bits, error) = self.leaves[i];
+        let leaf = (self.leaf_bits + bits, error, leaf_cost);
+        match self.split {
+            Some((_, split_bits, ref parts)) => {
+                let (bits, error) = parts.iter()
+                    .map(|part| part.cheapest(lambda))
+                    .fold((split_bits, 0.0), |(bits, error), part| (bits + part.0, error + part.1));
+                let cost = error + lambda * bits as f64;
+                if cost < leaf.2 { (bits, error, cost) } else { leaf }
+            }
+            None => leaf,
+        }
+    }
+
+    /// Appends the cheapest coding's splits in pre-order and its range blocks.
+    fn emit(&self, lambda: f64, partition: &mut Vec<Split>, mapping: &mut Vec<SquareMapping>) {
+        let (i, leaf_cost) = self.cheapest_leaf(lambda);
+        if let Some((split, _, ref parts)) = self.split {
+            if self.cheapest(lambda).2 < leaf_cost {
+                partition.push(split);
+                for part in parts.iter() {
+                    part.emit(lambda, partition, mapping);
+                }
+                return;
+            }
+        }
+        partition.push(Split::Leaf);
+        mapping.push(self.leaves[i].0);
+    }
+
+    /// Index into `leaves` and Lagrangian cost of the cheapest leaf.
+    fn cheapest_leaf(&self, lambda: f64) -> (usize, f64) {
+        self.leaves.iter()
+            .map(|&(_, bits, error)| error + lambda * (self.leaf_bits + bits) as f64)
+            .enumerate()
+            .fold(None, |best: Option<(usize, f64)>, c| match best {
+                Some(b) if b.1 <= c.1 => Some(b),
+                _ => Some(c),
+            })
+            .expect("no way to code a leaf")
+    }
+}
+
+/// The estimate for coding every candidate at `lambda` after `overhead` bits.
+fn estimate(candidates: &[Candidate], lambda: f64, pixels: usize, overhead: usize) -> RateDistortion {
+    let (bits, sqr_error) = candidates.iter()
+        .map(|c| c.cheapest(lambda))
+        .fold((overhead, 0.0), |(bits, error), c| (bits + c.0, error + c.1));
+    RateDistortion { bits, sqr_error, pixels }
+}
+
+/// Range of `lambda` bisected, in squared error per bit.
+const MIN_LAMBDA: f64 = 1e-3;
+const MAX_LAMBDA: f64 = 1e9;
+const BISECTIONS: usize = 60;
+
+/// Codes the trees of `candidates` over `pixels` pixels as well as `target`
+/// allows: at the fewest bits reaching a PSNR target, or the least error
+/// within a bit budget, which `overhead` bits stored besides the trees take
+/// from. Unreachable targets get the closest coding there is, so callers
+/// should check whether the estimate returned with the partition and
+/// mapping `meets` the target.
+pub fn optimize(candidates: &[Candidate], target: Target, pixels: usize, overhead: usize) -> (Vec<Split>, Vec<SquareMapping>, RateDistortion) {
+    // both rate and quality drop as `lambda` grows
+    let (mut lo, mut hi) = (MIN_LAMBDA.ln(), MAX_LAMBDA.ln());
+    let lambda = match target {
+        Target::Bpp(_) if !estimate(candidates, MAX_LAMBDA, pixels, overhead).meets(target) => MAX_LAMBDA,
+        Target::Psnr(_) if !estimate(candidates, MIN_LAMBDA, pixels, overhead).meets(target) => MIN_LAMBDA,
+        _ => {
+            for _ in 0..BISECTIONS {
+                let mid = (lo + hi) / 2.0;
+                let met = estimate(candidates, mid.exp(), pixels, overhead).meets(target);
+                // bit budgets want the smallest `lambda` that fits, quality
+                // targets the largest that still reaches them
+                match (target, met) {
+                    (Target::Bpp(_), true) | (Target::Psnr(_), false) => hi = mid,
+                    (Target::Bpp(_), false) | (Target::Psnr(_), true) => lo = mid,
+                }
+            }
+            match target {
+                Target::Bpp(_) => hi.exp(),
+                Target::Psnr(_) => lo.exp(),
+            }
+        }
+    };
+    let (mut partition, mut mapping) = (Vec::new(), Vec::new());
+    for candidate in candidates.iter() {
+        candidate.emit(lambda, &mut partition, &mut mapping);
+    }
+    (partition, mapping, estimate(candidates, lambda, pixels, overhead))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rd::*;
+    use byte_rect::{LinearCoeffs, RectCoords, Transform};
+
+    fn leaf(x: usize, side: usize, bits: usize, error: f64) -> Candidate {
+        let node = RectCoords { x, y: 0, width: side, height: side };
+        let map = SquareMapping { small: node, big: node, trans: Transform::HeadToTop, coeffs: LinearCoeffs { shift: 0, factor: 0.0 }, coarseness: 0 };
+        Candidate { leaves: vec![(map, bits, error), (SquareMapping { coarseness: 1, ..map }, bits / 2, 4.0 * error)], leaf_bits: 1, split: None }
+    }
+
+    // a 4x4 block that matches badly as a whole, but well in quarters
+    fn tree() -> Candidate {
+        let parts = (0..4).map(|i| leaf(i, 2, 16, 10.0)).collect();
+        Candidate { split: Some((Split::Quarters, 1, parts)), ..leaf(0, 4, 16, 1000.0) }
+    }
+
+    #[test]
+    fn more_bits_buy_less_error() {
+        let tree = tree();
+        let (cheap_partition, cheap, cheap_rd) = optimize(std::slice::from_ref(&tree), Target::Bpp(1.0), 16, 0);
+        assert_eq!(cheap_partition, vec![Split::Leaf]);
+        assert_eq!(cheap[0].coarseness, 1);
+        assert_eq!(cheap_rd.bits, 1 + 8);
+        let (partition, mapping, rd) = optimize(std::slice::from_ref(&tree), Target::Bpp(5.0), 16, 0);
+        assert_eq!(partition.len(), 5);
+        assert!(mapping.iter().all(|m| m.coarseness == 0));
+        assert_eq!(rd.bits, 1 + 4 * 17);
+        assert!(rd.psnr() > cheap_rd.psnr());
+    }
+
+    #[test]
+    fn overhead_takes_from_the_budget() {
+        let tree = tree();
+        // all four quarters at full precision no longer fit
+        let (_, mapping, rd) = optimize(std::slice::from_ref(&tree), Target::Bpp(5.0), 16, 16);
+        assert!(mapping.iter().any(|m| m.coarseness == 1));
+        assert!(rd.bits <= 5 * 16);
+        assert!(rd.meets(Target::Bpp(5.0)));
+        let (_, _, over) = optimize(std::slice::from_ref(&tree), Target::Bpp(1.0), 16, 16);
+        assert!(!over.meets(Target::Bpp(1.0)));
+    }
+
+    #[test]
+    fn quality_targets_take_the_fewest_bits_reaching_them() {
+        let tree = tree();
+        let (_, _, whole) = optimize(std::slice::from_ref(&tree), Target::Psnr(30.0), 16, 0);
+        assert_eq!(whole.bits, 1 + 16);
+        let (_, _, rd) = optimize(std::slice::from_ref(&tree), Target::Psnr(35.0), 16, 0);
+        assert!(rd.psnr() >= 35.0);
+        // coarse quarters already reach it
+        assert_eq!(rd.bits, 1 + 4 * 9);
+        let (_, _, best) = optimize(std::slice::from_ref(&tree), Target::Psnr(100.0), 16, 0);
+        assert_eq!(best.sqr_error, 40.0);
+    }
+}
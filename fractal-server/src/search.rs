@@ -0,0 +1,166 @@
+use std::cmp;
+
+use byte_rect::{LinearCoeffs, Transform};
+use domain_pool::{DomainPool, RangeBlock, SearchStats};
+use nearest::NearestDomains;
+use quant::CoeffQuantizer;
+
+/// How the encoder looks for the domain that matches a range block best.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Search {
+    /// Every domain in every transform.
+    Exhaustive,
+    /// Every transform of the smoothest `1/grouping_factor` of the domains.
+    Smoothest,
+    /// Domains of the range block's Fisher class, see `classify`.
+    Classified,
+    /// The `k` domains nearest in normalized feature space, see `nearest`.
+    Nearest { k: usize },
+    /// Domains at most `radius` pixels, in whole domain steps, away from the
+    /// one the range block lies in on either axis; tried ring by ring from the
+    /// inside with `spiral`, else smoothest first. The container stores them
+    /// as offsets, which takes fewer bits than a position on the whole grid.
+    Local { radius: usize, spiral: bool },
+}
+
+/// Picks the domain, transform and quantized coefficients for a range block,
+/// settling for the first candidate within `accept_mse`.
+pub trait SearchStrategy: Sync {
+    fn best_match(&self, pool: &DomainPool, range: &RangeBlock, quantizer: &CoeffQuantizer, accept_mse: f32) -> ((usize, Transform, LinearCoeffs), SearchStats);
+}
+
+impl Search {
+    pub fn strategy(self, pool: &DomainPool, grouping_factor: usize) -> Box<dyn SearchStrategy> {
+        match self {
+            Search::Exhaustive => Box::new(Exhaustive),
+            Search::Smoothest => Box::new(Smoothest { count: pool.domains().len() / grouping_factor }),
+            Search::Classified => Box::new(Classified),
+            Search::Nearest { k } => Box::new(NearestDomains::new(pool, k)),
+            Search::Local { radius, spiral } => Box::new(Local { radius, spiral }),
+        }
+    }
+}
+
+pub struct Exhaustive;
+
+impl SearchStrategy for Exhaustive {
+    fn best_match(&self, pool: &DomainPool, range: &RangeBlock, quantizer: &CoeffQuantizer, accept_mse: f32) -> ((usize, Transform, LinearCoeffs), SearchStats) {
+        pool.best_match(range, quantizer, accept_mse)
+    }
+}
+
+pub struct Smoothest {
+    pub count: usize,
+}
+
+impl SearchStrategy for Smoothest {
+    fn best_match(&self, pool: &DomainPool, range: &RangeBlock, quantizer: &CoeffQuantizer, accept_mse: f32) -> ((usize, Transform, LinearCoeffs), SearchStats) {
+        // the pool is sorted from the smoothest domain on
+        let candidates = (0..self.count.min(pool.domains().len()))
+            .flat_map(|i| Transform::ALL.iter().map(move |&t| (i, t)));
+        pool.best_of_or_all(candidates, range, quantizer, accept_mse)
+    }
+}
+
+/// Tries each domain of the range block's class in the one orientation that
+/// lines it up with the range block. Falls back to all of them when the class
+/// has no domains.
+pub struct Classified;
+
+impl SearchStrategy for Classified {
+    fn best_match(&self, pool: &DomainPool, range: &RangeBlock, quantizer: &CoeffQuantizer, accept_mse: f32) -> ((usize, Transform, LinearCoeffs), SearchStats) {
+        let candidates = pool.in_class(range.class.class).iter()
+            .map(|&i| (i, pool.domains()[i].class.transform_to(&range.class)));
+        pool.best_of_or_all(candidates, range, quantizer, accept_mse)
+    }
+}
+
+/// Falls back to all domains when there are none in the window, which only
+/// happens for range blocks that are not on the image the pool was made of.
+pub struct Local {
+    pub radius: usize,
+    pub spiral: bool,
+}
+
+impl Local {
+    /// Domains within the window around `range`, in search order.
+    pub fn window(&self, pool: &DomainPool, range: &RangeBlock) -> Vec<usize> {
+        let mut near = pool.domains().iter()
+            .enumerate()
+            .filter_map(|(i, d)| {
+                let step = pool.step();
+                let dx = (d.coords.x / step).abs_diff(range.coords.x / step);
+                let dy = (d.coords.y / step).abs_diff(range.coords.y / step);
+                let ring = cmp::max(dx, dy);
+                if ring * step > self.radius {
+                    return None;
+                }
+                Some((if self.spiral { ring } else { 0 }, i))
+            })
+            .collect::<Vec<_>>();
+        // stable, so the pool's smoothest first order stays within a ring
+        near.sort_by_key(|&(ring, _)| ring);
+        near.into_iter().map(|(_, i)| i).collect()
+    }
+}
+
+impl SearchStrategy for Local {
+    fn best_match(&self, pool: &DomainPool, range: &RangeBlock, quantizer: &CoeffQuantizer, accept_mse: f32) -> ((usize, Transform, LinearCoeffs), SearchStats) {
+        let candidates = self.window(pool, range)
+            .into_iter()
+            .flat_map(|i| Transform::ALL.iter().map(move |&t| (i, t)));
+        pool.best_of_or_all(candidates, range, quantizer, accept_mse)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use search::*;
+    use byte_rect::SquareCoords;
+    use gray_image::GrayImage;
+    use testing::{exact_quantizer, noise};
+
+    #[test]
+    fn every_strategy_finds_an_exact_copy_it_can_reach() {
+        let img = noise(16, 16);
+        let pool = DomainPool::new(&img, 8, 4);
+        let smoothest = pool.domains()[0].coords;
+        let target = pool.domains()[0].isometry(Transform::HeadToRight).clone();
+        // placed where the domain is, for the local search
+        let range = RangeBlock { coords: smoothest, ..RangeBlock::new(&target, SquareCoords { x: 0, y: 0, side: 4 }) };
+        for &search in [Search::Exhaustive, Search::Smoothest, Search::Classified, Search::Nearest { k: 4 }, Search::Local { radius: 0, spiral: true }].iter() {
+            let ((i, t, coeffs), _) = search.strategy(&pool, 4).best_match(&pool, &range, &exact_quantizer(), 0.0);
+            assert_eq!(pool.domains()[i].isometry(t), &target, "{:?}", search);
+            assert_eq!(coeffs.factor, 1.0, "{:?}", search);
+        }
+    }
+
+    #[test]
+    fn smoothest_only_looks_at_the_prefix() {
+        let img = noise(16, 16);
+        let pool = DomainPool::new(&img, 8, 4);
+        let target = pool.domains()[3].isometry(Transform::HeadToTop).clone();
+        let range = RangeBlock::new(&target, SquareCoords { x: 0, y: 0, side: 4 });
+        let ((i, _, _), _) = Search::Smoothest.strategy(&pool, 2).best_match(&pool, &range, &exact_quantizer(), 0.0);
+        assert!(i < 2);
+    }
+
+    #[test]
+    fn local_search_spirals_outwards_within_the_radius() {
+        let img = GrayImage::from_fn(32, 32, |x, y| (x * 7 + y * 3) as u8);
+        let pool = DomainPool::new(&img, 8, 4);
+        let range = RangeBlock::new(&img, SquareCoords { x: 12, y: 4, side: 4 });
+        let cells = |spiral| Local { radius: 11, spiral }.window(&pool, &range)
+            .into_iter()
+            .map(|i| (pool.domains()[i].coords.x / 8, pool.domains()[i].coords.y / 8))
+            .collect::<Vec<_>>();
+        let spiral = cells(true);
+        assert_eq!(spiral[0], (1, 0));
+        assert_eq!(spiral.len(), 6);
+        assert!(spiral[1..].iter().all(|&(x, y)| x <= 2 && y <= 1));
+        let mut unordered = cells(false);
+        unordered.sort();
+        assert_eq!(unordered, vec![(0, 0), (0, 1), (1, 0), (1, 1), (2, 0), (2, 1)]);
+    }
+}
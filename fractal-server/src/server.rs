@@ -0,0 +1,305 @@
+use std::io::{self, Cursor, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+
+use image::{self, ImageFormat};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use fractal_server::container::{self, Limits};
+use fractal_server::{codec, Compressed, Search};
+
+use cli::Options;
+
+#[derive(Debug, PartialEq)]
+pub struct Reply {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    fn ok(content_type: &'static str, body: Vec<u8>) -> Reply {
+        Reply { status: 200, content_type, body }
+    }
+
+    fn error(status: u16, msg: &str) -> Reply {
+        Reply { status, content_type: "text/plain; charset=utf-8", body: msg.as_bytes().to_vec() }
+    }
+}
+
+/// Limits keeping a single request from taking down the whole server.
+const MAX_BODY_BYTES: usize = 64 << 20;
+const MAX_PIXELS: usize = 1 << 24;
+const MAX_ITERATIONS: usize = 100;
+const MAX_THREADS: usize = 16;
+const MAX_NEIGHBOURS: usize = 1024;
+/// What a stream to decode may declare, checked while reading it.
+const STREAM_LIMITS: Limits = Limits { area: MAX_PIXELS, range_blocks: MAX_PIXELS / 16 };
+
+/// Undoes the `%XX` escapes of a query string component.
+fn percent_decode(s: &str) -> Result<String, String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b != b'%' {
+            bytes.push(b);
+            continue;
+        }
+        let hex = [iter.next(), iter.next()];
+        let digit = |d: Option<u8>| d.and_then(|d| (d as char).to_digit(16));
+        match (digit(hex[0]), digit(hex[1])) {
+            (Some(hi), Some(lo)) => bytes.push((hi * 16 + lo) as u8),
+            _ => return Err(format!("invalid escape in '{}'", s)),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| format!("'{}' is not UTF-8", s))
+}
+
+fn parse_query(query: &str) -> Result<Options, String> {
+    let mut options = Options::default();
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let mut parts = pair.splitn(2, '=');
+        let name = percent_decode(parts.next().unwrap())?;
+        let value = parts.next().map(percent_decode).transpose()?;
+        options.set(&name, value.as_ref())?;
+    }
+    options.validate()?;
+    let passes = options.decomp.iterations.max(options.decomp.refine_passes);
+    if passes > MAX_ITERATIONS {
+        return Err(format!("at most {} decoding passes are allowed", MAX_ITERATIONS));
+    }
+    if options.comp.threads > MAX_THREADS {
+        return Err(format!("at most {} threads are allowed", MAX_THREADS));
+    }
+    if let Search::Nearest { k } = options.comp.search {
+        if k > MAX_NEIGHBOURS {
+            return Err(format!("at most {} neighbours are allowed", MAX_NEIGHBOURS));
+        }
+    }
+    Ok(options)
+}
+
+/// Fails unless an image of `width x height` is small enough to work on.
+fn check_pixels(width: usize, height: usize) -> Result<(), Reply> {
+    match width.checked_mul(height) {
+        Some(pixels) if pixels <= MAX_PIXELS => Ok(()),
+        _ => Err(Reply::error(413, &format!("images may have at most {} pixels", MAX_PIXELS))),
+    }
+}
+
+/// The input image, unless it is too big.
+fn load_image(body: &[u8]) -> Result<image::DynamicImage, Reply> {
+    let img = image::load_from_memory(body).map_err(bad_request)?;
+    check_pixels(img.width() as usize, img.height() as usize)?;
+    Ok(img)
+}
+
+/// Fails unless every channel of `compressed` decodes in a small enough
+/// image, which is the padded one at the output scale.
+fn check_output(compressed: &[Compressed], options: &Options) -> Result<(), Reply> {
+    for comp in compressed.iter() {
+        let (width, height) = options.decomp.size.of(comp.orig_width, comp.orig_height);
+        let scale = |padded: usize, out: usize, orig: usize| padded.saturating_mul(out).div_ceil(orig);
+        check_pixels(scale(comp.padded_width, width, comp.orig_width), scale(comp.padded_height, height, comp.orig_height))?;
+    }
+    Ok(())
+}
+
+fn bad_request<E: ToString>(err: E) -> Reply {
+    Reply::error(400, &err.to_string())
+}
+
+fn encode(options: &Options, body: &[u8]) -> Result<Reply, Reply> {
+    let img = load_image(body)?;
+    let mut bytes = Vec::new();
+    let (compressed, stats, _) = codec::compress_image_with_stats(&img, options.comp).map_err(bad_request)?;
+    println!("{}", stats);
+    container::write(&compressed, options.coding, &mut bytes)
+        .map_err(|e| Reply::error(500, &e.to_string()))?;
+    Ok(Reply::ok("application/octet-stream", bytes))
+}
+
+fn decode(options: &Options, body: &[u8]) -> Result<Reply, Reply> {
+    let compressed = container::read_within(&mut &body[..], STREAM_LIMITS).map_err(bad_request)?;
+    check_output(&compressed, options)?;
+    let restored = codec::decompress_image(&compressed, options.decomp).map_err(bad_request)?;
+    let mut png = Cursor::new(Vec::new());
+    restored.write_to(&mut png, ImageFormat::Png).map_err(|e| Reply::error(500, &e.to_string()))?;
+    Ok(Reply::ok("image/png", png.into_inner()))
+}
+
+fn json_number(x: f64) -> String {
+    if x.is_finite() { format!("{:.3}", x) } else { "null".to_string() }
+}
+
+fn roundtrip(options: &Options, body: &[u8]) -> Result<Reply, Reply> {
+    let img = load_image(body)?;
+    let mut bytes = Vec::new();
+    let (compressed, stats, _) = codec::compress_image_with_stats(&img, options.comp).map_err(bad_request)?;
+    println!("{}", stats);
+    container::write(&compressed, options.coding, &mut bytes)
+        .map_err(|e| Reply::error(500, &e.to_string()))?;
+    let compressed = container::read(&mut &bytes[..]).map_err(|e| Reply::error(500, &e.to_string()))?;
+    check_output(&compressed, options)?;
+    let restored = codec::decompress_image(&compressed, options.decomp).map_err(|e| Reply::error(500, &e.to_string()))?;
+    let pixels = (img.width() * img.height()) as f64;
+    // a zoomed decoding has nothing to be compared with
+    let psnr = if restored.dimensions() == (img.width(), img.height()) { codec::psnr(&img.to_rgb8(), &restored) } else { f64::NAN };
+    let json = format!(
+        "{{\"input_bytes\":{},\"compressed_bytes\":{},\"bpp\":{},\"psnr\":{}}}",
+        body.len(), bytes.len(), json_number(bytes.len() as f64 * 8.0 / pixels), json_number(psnr));
+    Ok(Reply::ok("application/json", json.into_bytes()))
+}
+
+/// Routes one request; kept free of any networking so it can be tested directly.
+///
+/// * `POST /encode` takes an image and replies with the compressed stream,
+/// * `POST /decode` takes a compressed stream and replies with a PNG,
+/// * `POST /roundtrip` takes an image and replies with sizes and PSNR as JSON.
+///
+/// Settings are passed in the query string, e.g. `/encode?small-square-size=8`.
+/// A request that panics gets a 500 reply.
+pub fn handle(method: &Method, url: &str, body: &[u8]) -> Reply {
+    panic::catch_unwind(AssertUnwindSafe(|| route(method, url, body)))
+        .unwrap_or_else(|_| Reply::error(500, "internal error"))
+}
+
+fn route(method: &Method, url: &str, body: &[u8]) -> Reply {
+    let mut parts = url.splitn(2, '?');
+    let path = parts.next().unwrap();
+    let endpoint: fn(&Options, &[u8]) -> Result<Reply, Reply> = match path {
+        "/encode" => encode,
+        "/decode" => decode,
+        "/roundtrip" => roundtrip,
+        _ => return Reply::error(404, "not found"),
+    };
+    match *method {
+        Method::Options => return Reply::ok("text/plain", Vec::new()),
+        Method::Post => (),
+        _ => return Reply::error(405, "only POST is supported"),
+    }
+    let options = match parse_query(parts.next().unwrap_or("")) {
+        Ok(options) => options,
+        Err(msg) => return Reply::error(400, &msg),
+    };
+    endpoint(&options, body).unwrap_or_else(|err| err)
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+fn respond(mut request: Request) -> io::Result<()> {
+    let mut body = Vec::new();
+    request.as_reader().take(MAX_BODY_BYTES as u64 + 1).read_to_end(&mut body)?;
+    let reply = if body.len() > MAX_BODY_BYTES {
+        Reply::error(413, "request body is too big")
+    } else {
+        handle(request.method(), request.url(), &body)
+    };
+    println!("{} {} -> {}", request.method(), request.url(), reply.status);
+    let response = Response::from_data(reply.body)
+        .with_status_code(reply.status)
+        .with_header(header("Content-Type", reply.content_type))
+        // the image-tabs frontend is served from a different origin
+        .with_header(header("Access-Control-Allow-Origin", "*"))
+        .with_header(header("Access-Control-Allow-Methods", "POST, OPTIONS"))
+        .with_header(header("Access-Control-Allow-Headers", "Content-Type"));
+    request.respond(response)
+}
+
+/// Serves requests until the process is killed, each one on its own thread.
+pub fn serve(addr: &str) -> io::Result<()> {
+    let server = Server::http(addr).map_err(|e| io::Error::other(e.to_string()))?;
+    println!("listening on http://{}", addr);
+    for request in server.incoming_requests() {
+        thread::spawn(move || {
+            if let Err(err) = respond(request) {
+                eprintln!("failed to respond: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use server::*;
+    use image::{DynamicImage, RgbImage};
+
+    fn png() -> Vec<u8> {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(16, 8, |x, y| {
+            image::Rgb([(x * 16) as u8, (y * 32) as u8, 128])
+        }));
+        let mut bytes = Cursor::new(Vec::new());
+        img.write_to(&mut bytes, ImageFormat::Png).unwrap();
+        bytes.into_inner()
+    }
+
+    const SETTINGS: &str = "big-square-size=8&small-square-size=4&grouping-factor=1";
+
+    #[test]
+    fn encoded_stream_decodes_into_png_of_same_size() {
+        let encoded = handle(&Method::Post, &format!("/encode?{}", SETTINGS), &png());
+        assert_eq!(encoded.status, 200);
+        let decoded = handle(&Method::Post, "/decode?iterations=5", &encoded.body);
+        assert_eq!(decoded.status, 200);
+        assert_eq!(decoded.content_type, "image/png");
+        let img = image::load_from_memory(&decoded.body).unwrap();
+        assert_eq!((img.width(), img.height()), (16, 8));
+        let zoomed = handle(&Method::Post, "/decode?size=32x12", &encoded.body);
+        let img = image::load_from_memory(&zoomed.body).unwrap();
+        assert_eq!((img.width(), img.height()), (32, 12));
+    }
+
+    #[test]
+    fn roundtrip_reports_sizes_and_psnr() {
+        let input = png();
+        let reply = handle(&Method::Post, &format!("/roundtrip?{}", SETTINGS), &input);
+        assert_eq!(reply.status, 200);
+        let json = String::from_utf8(reply.body).unwrap();
+        assert!(json.starts_with(&format!("{{\"input_bytes\":{},\"compressed_bytes\":", input.len())), "{}", json);
+        assert!(json.contains("\"psnr\":"), "{}", json);
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        assert_eq!(handle(&Method::Post, "/squash", &png()).status, 404);
+        assert_eq!(handle(&Method::Get, "/encode", &[]).status, 405);
+        assert_eq!(handle(&Method::Post, "/encode?small-square-size=3", &png()).status, 400);
+        assert_eq!(handle(&Method::Post, "/encode", b"not an image").status, 400);
+        assert_eq!(handle(&Method::Post, "/decode", b"not a stream").status, 400);
+    }
+
+    #[test]
+    fn rejects_requests_over_the_limits() {
+        let encoded = handle(&Method::Post, &format!("/encode?{}", SETTINGS), &png());
+        assert_eq!(handle(&Method::Post, "/decode?size=1000000x1000000", &encoded.body).status, 413);
+        assert_eq!(handle(&Method::Post, "/decode?scale=10000", &encoded.body).status, 413);
+        assert_eq!(handle(&Method::Post, "/decode?iterations=1000000", &encoded.body).status, 400);
+        assert_eq!(handle(&Method::Post, "/encode?search=nearest:100000000000000", &png()).status, 400);
+        assert_eq!(handle(&Method::Post, "/encode?threads=100000", &png()).status, 400);
+    }
+
+    #[test]
+    fn rejects_streams_too_big_to_decode_before_reading_them() {
+        let mut stream = handle(&Method::Post, &format!("/encode?{}", SETTINGS), &png()).body;
+        // a single 8192x8192 channel of 8x8 range blocks, which the
+        // container alone would go on reading
+        stream[6] = 1;
+        for &offset in [7, 11, 15, 19].iter() {
+            stream[offset..offset + 4].copy_from_slice(&8192u32.to_le_bytes());
+        }
+        let reply = handle(&Method::Post, "/decode", &stream);
+        assert_eq!(reply.status, 400);
+        assert!(String::from_utf8(reply.body).unwrap().contains("too big"));
+    }
+
+    #[test]
+    fn query_values_are_percent_decoded() {
+        let options = parse_query("search=spiral%3A8").unwrap();
+        assert_eq!(options.comp.search, Search::Local { radius: 8, spiral: true });
+        assert!(parse_query("search=spiral%3").is_err());
+    }
+}
@@ -0,0 +1,31 @@
+// Pictures and helpers the unit tests of several modules share.
+
+use byte_rect::ByteRect;
+use fractal::{decompress, Compressed, DecompSettings};
+use gray_image::GrayImage;
+use quant::CoeffQuantizer;
+
+/// A pixel of `noise`.
+pub fn noise_at(x: usize, y: usize) -> u8 {
+    ((x * 29 + y * 47 + x * x * y) % 256) as u8
+}
+
+/// A `width x height` picture without any structure the search could exploit.
+pub fn noise(width: usize, height: usize) -> GrayImage {
+    GrayImage::from_fn(width, height, noise_at)
+}
+
+/// A `width x height` picture of the pixels `f` gives, as rows.
+pub fn picture<F>(width: usize, height: usize, f: F) -> Vec<Vec<u8>> where F: FnMut(usize, usize) -> u8 {
+    GrayImage::from_fn(width, height, f).to_rows()
+}
+
+/// How far the default decoding of `comp` is from `picture`.
+pub fn decoded_dist(picture: &[Vec<u8>], comp: &Compressed) -> u64 {
+    GrayImage::from_rows(picture).dist(&GrayImage::from_rows(&decompress(comp, DecompSettings::default())))
+}
+
+/// A quantizer that leaves room for a factor of exactly 1.
+pub fn exact_quantizer() -> CoeffQuantizer {
+    CoeffQuantizer { max_factor: 2.0, ..CoeffQuantizer::default() }
+}
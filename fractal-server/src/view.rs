@@ -0,0 +1,154 @@
+use byte_rect::{LinearCoeffs, MatchSums, Transform};
+
+/// Read-only access to a rectangle of pixels, wherever and however they are stored.
+pub trait PixelSource {
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+    fn at(&self, x: usize, y: usize) -> u8;
+
+    fn sum(&self) -> i64 {
+        let mut sum = 0;
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                sum += self.at(x, y) as i64;
+            }
+        }
+        sum
+    }
+
+    fn sqr_sum(&self) -> i64 {
+        let mut sum = 0;
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                let p = self.at(x, y) as i64;
+                sum += p * p;
+            }
+        }
+        sum
+    }
+
+    #[allow(dead_code)]
+    fn prod_sum<O: PixelSource>(&self, other: &O) -> i64 where Self: Sized {
+        check_same_size(self, other);
+        let mut sum = 0;
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                sum += self.at(x, y) as i64 * other.at(x, y) as i64;
+            }
+        }
+        sum
+    }
+
+    fn dist<O: PixelSource>(&self, other: &O) -> u64 where Self: Sized {
+        self.linear_dist(LinearCoeffs { shift: 0, factor: 1.0 }, other)
+    }
+
+    /// Same as `ByteRect::dist` between `self.linear(c)` and `other`, without
+    /// building the intermediate rectangle.
+    fn linear_dist<O: PixelSource>(&self, c: LinearCoeffs, other: &O) -> u64 where Self: Sized {
+        check_same_size(self, other);
+        let mut dist = 0;
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                let diff = c.apply(self.at(x, y)) as i64 - other.at(x, y) as i64;
+                dist += (diff * diff) as u64;
+            }
+        }
+        dist
+    }
+
+    /// Sums needed to fit `self` onto `other`, see `MatchSums::coeffs`.
+    #[allow(dead_code)]
+    fn match_sums<O: PixelSource>(&self, other: &O) -> MatchSums where Self: Sized {
+        MatchSums {
+            count: (self.width() * self.height()) as i64,
+            self_sum: self.sum(),
+            self_sqr_sum: self.sqr_sum(),
+            other_sum: other.sum(),
+            prod_sum: self.prod_sum(other),
+        }
+    }
+}
+
+fn check_same_size<A: PixelSource, B: PixelSource>(a: &A, b: &B) {
+    if a.width() != b.width() || a.height() != b.height() {
+        panic!("can't handle different dimensions {}x{} and {}x{}",
+            a.width(), a.height(), b.width(), b.height());
+    }
+}
+
+/// `source` as seen through an isometry: pixels are looked up in the source on
+/// every read, so nothing is copied.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct Transformed<'a, S: 'a> {
+    source: &'a S,
+    trans: Transform,
+}
+
+impl<'a, S: PixelSource> Transformed<'a, S> {
+    #[allow(dead_code)]
+    pub fn new(source: &'a S, trans: Transform) -> Transformed<'a, S> {
+        Transformed { source, trans }
+    }
+}
+
+impl<'a, S: PixelSource> PixelSource for Transformed<'a, S> {
+    fn width(&self) -> usize {
+        if self.trans.transposes() { self.source.height() } else { self.source.width() }
+    }
+
+    fn height(&self) -> usize {
+        if self.trans.transposes() { self.source.width() } else { self.source.height() }
+    }
+
+    fn at(&self, x: usize, y: usize) -> u8 {
+        let (src_x, src_y) = self.trans.source_of(x, y, self.source.width(), self.source.height());
+        self.source.at(src_x, src_y)
+    }
+
+    // the sums don't depend on the pixel order
+    fn sum(&self) -> i64 {
+        self.source.sum()
+    }
+
+    fn sqr_sum(&self) -> i64 {
+        self.source.sqr_sum()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use view::*;
+    use byte_rect::ByteRect;
+    use gray_image::GrayImage;
+
+    fn image() -> GrayImage {
+        GrayImage::from_fn(3, 5, |x, y| (x * 41 + y * 13 + x * y * 7) as u8)
+    }
+
+    #[test]
+    fn transformed_view_reads_like_the_transformed_image() {
+        let img = image();
+        let view = img.as_view();
+        for &t in Transform::ALL.iter() {
+            let transformed = Transformed::new(&view, t);
+            let expected = img.transform(t);
+            assert_eq!((transformed.width(), transformed.height()), (expected.width(), expected.height()));
+            assert_eq!(transformed.dist(&expected.as_view()), 0, "{:?}", t);
+        }
+    }
+
+    #[test]
+    fn statistics_match_the_materialized_operations() {
+        let img = image();
+        let other = img.transform(Transform::HeadToBottomInv);
+        let view = img.as_view();
+        let transformed = Transformed::new(&view, Transform::HeadToBottomInv);
+        let coeffs = LinearCoeffs { shift: 30, factor: -0.6 };
+        assert_eq!(view.linear_dist(coeffs, &other.as_view()), img.linear(coeffs).dist(&other));
+        assert_eq!(transformed.match_sums(&view).coeffs(0.8), other.best_coeffs_to_match_within(&img, 0.8));
+        assert_eq!(transformed.prod_sum(&view), other.as_view().prod_sum(&view));
+    }
+}
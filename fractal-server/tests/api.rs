@@ -0,0 +1,39 @@
+extern crate fractal_server;
+
+use fractal_server::container::{self, Coding};
+use fractal_server::*;
+
+fn gradient(width: usize, height: usize) -> Vec<Vec<u8>> {
+    (0..height)
+        .map(|y| (0..width).map(|x| (x * 7 + y * 3) as u8).collect())
+        .collect()
+}
+
+#[test]
+fn compressed_channel_survives_the_container_and_decodes() {
+    let picture = gradient(20, 12);
+    // none of the encoder-only settings can come back from the container
+    let settings = CompSettings {
+        big_square_size: 8,
+        small_square_size: 4,
+        grouping_factor: 1,
+        search: Search::Exhaustive,
+        threads: 2,
+        accept_mse: 1.0,
+        split_depth: 1,
+        split_mse: 5.0,
+        target: Some(Target::Psnr(30.0)),
+        ..CompSettings::default()
+    };
+    let compressed = compress(&picture, settings).unwrap();
+    assert_eq!(compressed.settings, settings.stored());
+    assert_eq!(compressed.settings.target, None);
+    let mut bytes = Vec::new();
+    container::write(std::slice::from_ref(&compressed), Coding::Range, &mut bytes).unwrap();
+    let restored = container::read(&mut &bytes[..]).unwrap();
+    assert_eq!(restored, vec![compressed]);
+    let decoded = decompress(&restored[0], DecompSettings::default());
+    assert_eq!((decoded.len(), decoded[0].len()), (12, 20));
+    let dist = picture.iter().flatten().zip(decoded.iter().flatten()).map(|(&a, &b)| (a as i32 - b as i32).pow(2)).sum::<i32>();
+    assert!(dist < 20 * 12 * 4 * 4);
+}